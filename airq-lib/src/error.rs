@@ -7,10 +7,24 @@ pub enum Error {
     RequestError(reqwest::Error),
     Base64Error(base64::DecodeError),
     DecryptionError(BlockModeError),
-    JsonError(serde_json::Error)
+    JsonError(serde_json::Error),
+    MdnsError(zeroconf::error::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Short name of the kind of error, e.g. for metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::RequestError(_) => "request",
+            Error::Base64Error(_) => "base64",
+            Error::DecryptionError(_) => "decryption",
+            Error::JsonError(_) => "json",
+            Error::MdnsError(_) => "mdns",
+        }
+    }
+}
 impl From<reqwest::Error> for Error {
     fn from(e: reqwest::Error) -> Self {
         Error::RequestError(e)
@@ -31,6 +45,11 @@ impl From<serde_json::Error> for Error {
         Error::JsonError(e.into())
     }
 }
+impl From<zeroconf::error::Error> for Error {
+    fn from(e: zeroconf::error::Error) -> Self {
+        Error::MdnsError(e)
+    }
+}
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -39,6 +58,7 @@ impl fmt::Display for Error {
             Error::Base64Error(e) => write!(f, "base64 decoding error: {}", e),
             Error::DecryptionError(e) => write!(f, "decryption error: {}", e),
             Error::JsonError(e) => write!(f, "JSON deserialization error: {}", e),
+            Error::MdnsError(e) => write!(f, "mDNS error: {}", e),
         }
     }
 }
@@ -50,6 +70,7 @@ impl std::error::Error for Error {
             Error::Base64Error(e) => e,
             Error::DecryptionError(e) => e,
             Error::JsonError(e) => e,
+            Error::MdnsError(e) => e,
         })
     }
 }
@@ -1,6 +1,4 @@
-#[cfg(feature = "blocking")]
-use std::time::Instant;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 #[cfg(feature = "blocking")]
 use std::thread;
 #[cfg(feature = "blocking")]
@@ -28,6 +26,7 @@ pub use base64::DecodeError;
 
 type Aes256Cbc = Cbc<Aes256, Pkcs7>;
 
+#[derive(Clone)]
 pub struct AirQ {
     key: [u8; 32],
     prefix: String,
@@ -44,7 +43,26 @@ impl AirQ {
         }
     }
 
+    /// Blocks until an airQ is found in the network via mDNS and returns its address.
+    ///
+    /// If mDNS fails, e.g. because no mDNS daemon is running, it is retried after a backoff of up to a minute.
     pub fn find_in_network() -> String {
+        let mut backoff = Duration::from_secs(1);
+        loop {
+            match Self::discover(Duration::from_secs(60)) {
+                Ok(Some(addr)) => return addr,
+                // the search itself took a minute
+                Ok(None) => backoff = Duration::from_secs(1),
+                Err(_) => {
+                    std::thread::sleep(backoff);
+                    backoff = (backoff * 2).min(Duration::from_secs(60));
+                },
+            }
+        }
+    }
+
+    /// Searches the network for an airQ via mDNS for at most `timeout`.
+    pub fn discover(timeout: Duration) -> Result<Option<String>> {
         let airq = Arc::new(Mutex::new(None));
         let airq2 = Arc::clone(&airq);
 
@@ -58,12 +76,13 @@ impl AirQ {
 
         let mut browser = MdnsBrowser::new("_http._tcp");
         browser.set_service_discovered_callback(Box::new(callback));
-        let event_loop = browser.browse_services().unwrap();
-        while airq.lock().unwrap().is_none() {
-            event_loop.poll(Duration::from_secs(0)).unwrap();
+        let event_loop = browser.browse_services()?;
+        let start = Instant::now();
+        while airq.lock().unwrap().is_none() && start.elapsed() < timeout {
+            event_loop.poll(Duration::from_millis(100))?;
         }
-        let addr = airq.lock().unwrap().take().unwrap();
-        addr
+        let addr = airq.lock().unwrap().take();
+        Ok(addr)
     }
 
     fn client(&self) -> Client {
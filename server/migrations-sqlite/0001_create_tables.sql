@@ -0,0 +1,61 @@
+CREATE TABLE devices (
+    -- DeviceID reported by the airQ
+    id TEXT PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL
+);
+CREATE TABLE files (
+    id INTEGER PRIMARY KEY NOT NULL,
+    device TEXT NOT NULL,
+    year INTEGER NOT NULL,
+    month INTEGER NOT NULL,
+    day INTEGER NOT NULL,
+    timestamp INTEGER NOT NULL,
+    UNIQUE (device, year, month, day, timestamp)
+);
+CREATE TABLE measurements (
+    device TEXT NOT NULL,
+    -- unix timestamp in ms
+    timestamp INTEGER NOT NULL,
+    -- null for manual readouts
+    file INTEGER REFERENCES files (id),
+    health REAL NOT NULL,
+    performance REAL NOT NULL,
+    tvoc REAL,
+    humidity REAL NOT NULL,
+    humidity_abs REAL NOT NULL,
+    temperature REAL NOT NULL,
+    dewpt REAL NOT NULL,
+    sound REAL NOT NULL,
+    -- 10^(sound / 10) to average sound levels energetically without math functions
+    sound_energy REAL NOT NULL,
+    pressure REAL NOT NULL,
+    no2 REAL,
+    co REAL,
+    co2 REAL NOT NULL,
+    pm1 REAL NOT NULL,
+    pm2_5 REAL NOT NULL,
+    pm10 REAL NOT NULL,
+    oxygen REAL NOT NULL,
+    o3 REAL,
+    so2 REAL,
+    uptime INTEGER NOT NULL,
+    measuretime REAL NOT NULL,
+    -- JSON
+    status TEXT NOT NULL,
+    bat REAL,
+    door_event REAL,
+    window_open REAL,
+    humidity_abs_delta REAL,
+    co2_delta REAL,
+    cnt0_3 REAL NOT NULL,
+    cnt0_5 REAL NOT NULL,
+    cnt1 REAL NOT NULL,
+    cnt2_5 REAL NOT NULL,
+    cnt5 REAL NOT NULL,
+    cnt10 REAL NOT NULL,
+    typ_ps REAL NOT NULL,
+    -- JSON object of the fields unknown to the server
+    rest TEXT NOT NULL,
+    PRIMARY KEY (device, timestamp)
+) WITHOUT ROWID;
+CREATE INDEX measurements_manual_readout ON measurements (timestamp) WHERE file IS NULL;
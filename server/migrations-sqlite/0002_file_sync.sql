@@ -0,0 +1,16 @@
+-- download state of each file on the airQ, so that files which failed to download are retried
+CREATE TABLE file_sync (
+    device TEXT NOT NULL,
+    year INTEGER NOT NULL,
+    month INTEGER NOT NULL,
+    day INTEGER NOT NULL,
+    timestamp INTEGER NOT NULL,
+    -- pending, succeeded or failed
+    state TEXT NOT NULL,
+    -- number of failed downloads
+    attempts INTEGER NOT NULL DEFAULT 0,
+    error TEXT,
+    -- unix timestamp in ms
+    updated INTEGER NOT NULL,
+    PRIMARY KEY (device, year, month, day, timestamp)
+) WITHOUT ROWID;
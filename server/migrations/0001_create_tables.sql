@@ -1,3 +1,4 @@
+-- schema of the former createdb.sql, existing databases already have these tables
 CREATE TABLE IF NOT EXISTS files (
     id serial8 PRIMARY KEY NOT NULL,
     year int2 NOT NULL,
@@ -30,4 +31,3 @@ CREATE TABLE IF NOT EXISTS measurements (
     so2 float8,
     FOREIGN KEY (file) REFERENCES files (id)
 );
-CREATE INDEX IF NOT EXISTS measurements_manual_readout ON measurements (timestamp) WHERE file IS NULL;
@@ -0,0 +1,24 @@
+CREATE TABLE IF NOT EXISTS devices (
+    -- DeviceID reported by the airQ
+    id text PRIMARY KEY NOT NULL,
+    name text NOT NULL
+);
+-- existing rows are assigned to the configured `legacy_device` when it registers itself
+ALTER TABLE files ADD COLUMN IF NOT EXISTS device text NOT NULL DEFAULT '';
+ALTER TABLE files ALTER COLUMN device DROP DEFAULT;
+ALTER TABLE files DROP CONSTRAINT IF EXISTS files_year_month_day_timestamp_key;
+CREATE UNIQUE INDEX IF NOT EXISTS files_device_year_month_day_timestamp_key ON files (device, year, month, day, timestamp);
+ALTER TABLE measurements ADD COLUMN IF NOT EXISTS device text NOT NULL DEFAULT '';
+ALTER TABLE measurements ALTER COLUMN device DROP DEFAULT;
+-- the primary key is named after the table it was created as, e.g. `measurements2_pkey` in databases migrated from iot2db
+DO $$
+DECLARE
+    pkey text;
+BEGIN
+    SELECT conname INTO pkey FROM pg_constraint WHERE conrelid = 'measurements'::regclass AND contype = 'p';
+    IF pkey IS NOT NULL THEN
+        EXECUTE format('ALTER TABLE measurements DROP CONSTRAINT %I', pkey);
+    END IF;
+END
+$$;
+ALTER TABLE measurements ADD PRIMARY KEY (device, timestamp);
@@ -0,0 +1,15 @@
+-- layout shared with iot2db: manual readouts are marked by `persistent` instead of missing a file
+-- databases migrated by hand following the former README already have this layout
+DO $$
+BEGIN
+    IF EXISTS (SELECT FROM information_schema.columns WHERE table_name = 'measurements' AND column_name = 'file') THEN
+        ALTER TABLE measurements ADD COLUMN persistent bool NOT NULL GENERATED ALWAYS AS (file IS NOT NULL) STORED;
+        ALTER TABLE measurements ALTER COLUMN persistent DROP EXPRESSION;
+        ALTER TABLE measurements DROP CONSTRAINT IF EXISTS measurements_file_fkey;
+        ALTER TABLE measurements DROP COLUMN file;
+    END IF;
+END
+$$;
+DROP TABLE IF EXISTS files;
+DROP INDEX IF EXISTS measurements_manual_readout;
+CREATE INDEX measurements_manual_readout ON measurements (timestamp) WHERE NOT persistent;
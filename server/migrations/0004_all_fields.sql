@@ -0,0 +1,19 @@
+-- all fields sent by the airQ, the measurement uncertainties aren't stored
+ALTER TABLE measurements
+    ADD COLUMN uptime int8,
+    ADD COLUMN measuretime float8,
+    ADD COLUMN status jsonb,
+    ADD COLUMN bat float8,
+    ADD COLUMN door_event float8,
+    ADD COLUMN window_open float8,
+    ADD COLUMN humidity_abs_delta float8,
+    ADD COLUMN co2_delta float8,
+    ADD COLUMN cnt0_3 float8,
+    ADD COLUMN cnt0_5 float8,
+    ADD COLUMN cnt1 float8,
+    ADD COLUMN cnt2_5 float8,
+    ADD COLUMN cnt5 float8,
+    ADD COLUMN cnt10 float8,
+    ADD COLUMN typ_ps float8,
+    -- fields unknown to the server, e.g. sensors added by newer firmware
+    ADD COLUMN rest jsonb;
@@ -0,0 +1,16 @@
+-- download state of each file on the airQ, so that files which failed to download are retried
+CREATE TABLE file_sync (
+    device text NOT NULL,
+    year int4 NOT NULL,
+    month int4 NOT NULL,
+    day int4 NOT NULL,
+    timestamp int8 NOT NULL,
+    -- pending, succeeded or failed
+    state text NOT NULL,
+    -- number of failed downloads
+    attempts int4 NOT NULL DEFAULT 0,
+    error text,
+    updated timestamptz NOT NULL DEFAULT now(),
+    PRIMARY KEY (device, year, month, day, timestamp)
+);
+CREATE INDEX file_sync_unsynced ON file_sync (device) WHERE state <> 'succeeded';
@@ -0,0 +1,37 @@
+-- hourly and daily aggregates of each numeric field, maintained by `store_entries` for ranges of many days
+-- manual readouts aren't part of the rollups, the buckets since the last downloaded measurement are read from `measurements`
+CREATE TABLE rollups (
+    device text NOT NULL,
+    -- size of the bucket in seconds
+    resolution int4 NOT NULL,
+    bucket timestamptz NOT NULL,
+    -- column name, or `rest.<key>` for fields in `rest`
+    field text NOT NULL,
+    min float8 NOT NULL,
+    max float8 NOT NULL,
+    -- for `sound` the sum of the sound energy 10^(sound / 10) to average it energetically
+    sum float8 NOT NULL,
+    count int8 NOT NULL,
+    PRIMARY KEY (device, resolution, bucket, field)
+);
+
+-- numeric fields of a measurement converted with `to_jsonb`, of `[value, uncertainty]` in `rest` only the value
+CREATE FUNCTION measurement_fields(measurement jsonb) RETURNS TABLE (field text, value float8)
+LANGUAGE sql IMMUTABLE AS $$
+    SELECT key, (value #>> '{}')::float8
+    FROM jsonb_each(measurement)
+    WHERE jsonb_typeof(value) = 'number'
+    UNION ALL
+    SELECT 'rest.' || key, CASE jsonb_typeof(value) WHEN 'array' THEN (value ->> 0)::float8 ELSE (value #>> '{}')::float8 END
+    FROM jsonb_each(measurement -> 'rest')
+    WHERE jsonb_typeof(value) = 'number' OR jsonb_typeof(value -> 0) = 'number'
+$$;
+
+-- the measurements stored before the rollups existed are rolled up in the background a day at a time,
+-- `next` is the start of the next day to roll up and `until` when the rollups were created
+CREATE TABLE rollup_backfill (
+    next timestamptz NOT NULL,
+    until timestamptz NOT NULL
+);
+INSERT INTO rollup_backfill (next, until)
+SELECT date_bin('1 day', min(timestamp), 'epoch'), now() FROM measurements HAVING count(*) > 0;
@@ -0,0 +1,2 @@
+-- number of readouts a row of the retention rules averages, to weight it when its bucket is downsampled again
+ALTER TABLE measurements ADD COLUMN samples int4 NOT NULL DEFAULT 1;
@@ -0,0 +1,126 @@
+use std::collections::BTreeMap;
+
+use chrono::{NaiveDate, TimeZone, Timelike};
+use chrono_tz::Tz;
+use serde::Serialize;
+
+use crate::Measurement;
+
+const HOUR_MILLIS: i64 = 60 * 60 * 1000;
+
+/// Averages sound levels in dB by their energy instead of arithmetically.
+pub fn energetic_mean(levels: impl IntoIterator<Item = f64>) -> f64 {
+    let (sum, count) = levels.into_iter()
+        .fold((0., 0), |(sum, count), level| (sum + 10f64.powf(level / 10.), count + 1));
+    10. * (sum / count as f64).log10()
+}
+
+#[derive(Debug, Serialize)]
+pub struct Levels {
+    /// start of the period
+    timestamp: i64,
+    /// equivalent continuous sound level
+    leq: f64,
+    /// level exceeded 10% of the time
+    l10: f64,
+    /// level exceeded 50% of the time
+    l50: f64,
+    /// level exceeded 90% of the time, i.e. the background noise
+    l90: f64,
+    lmax: f64,
+    samples: usize,
+}
+
+impl Levels {
+    fn new(timestamp: i64, mut levels: Vec<f64>) -> Levels {
+        levels.sort_by(f64::total_cmp);
+        let exceeded = |percent: f64| levels[((1. - percent / 100.) * (levels.len() - 1) as f64).round() as usize];
+        Levels {
+            timestamp,
+            leq: energetic_mean(levels.iter().copied()),
+            l10: exceeded(10.),
+            l50: exceeded(50.),
+            l90: exceeded(90.),
+            lmax: *levels.last().unwrap(),
+            samples: levels.len(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AcousticReport {
+    hourly: Vec<Levels>,
+    /// statistics of each night from 22:00 to 06:00 in the configured time zone
+    nights: Vec<Levels>,
+}
+
+/// Computes the levels of each hour and of each night in the given time zone.
+pub fn report(measurements: &[Measurement], timezone: Tz) -> AcousticReport {
+    let mut hours: BTreeMap<i64, Vec<f64>> = BTreeMap::new();
+    let mut nights: BTreeMap<NaiveDate, Vec<f64>> = BTreeMap::new();
+    for (timestamp, sound) in measurements.iter().filter_map(|m| Some((m.timestamp?, m.sound.filter(|sound| sound.is_finite())?))) {
+        hours.entry(timestamp - timestamp.rem_euclid(HOUR_MILLIS)).or_default().push(sound);
+
+        let time = timezone.timestamp_millis(timestamp);
+        // nights are attributed to the day they start on
+        match time.hour() {
+            22..=23 => nights.entry(time.date().naive_local()).or_default().push(sound),
+            0..=5 => nights.entry(time.date().naive_local().pred()).or_default().push(sound),
+            _ => (),
+        }
+    }
+
+    AcousticReport {
+        hourly: hours.into_iter()
+            .map(|(timestamp, levels)| Levels::new(timestamp, levels))
+            .collect(),
+        nights: nights.into_iter()
+            .map(|(date, levels)| {
+                // 22:00 doesn't exist if the clocks are put forward then
+                let start = [22, 23].iter()
+                    .find_map(|&hour| timezone.from_local_datetime(&date.and_hms(hour, 0, 0)).earliest())
+                    .unwrap_or_else(|| timezone.from_utc_datetime(&date.and_hms(22, 0, 0)));
+                Levels::new(start.timestamp_millis(), levels)
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    #[test]
+    fn energetic_means() {
+        assert!((energetic_mean([40., 40.]) - 40.).abs() < 1e-9);
+        // dominated by the louder level
+        assert!((energetic_mean([40., 50.]) - 10. * 55_000f64.log10()).abs() < 1e-9);
+        assert!((energetic_mean([30., 60.]) - 57.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn percentile_levels() {
+        let levels = Levels::new(0, (1..=11).rev().map(|level| level as f64 * 10.).collect());
+        assert_eq!((levels.l10, levels.l50, levels.l90, levels.lmax, levels.samples), (100., 60., 20., 110., 11));
+        // sorting doesn't panic on NaN
+        assert_eq!(Levels::new(0, vec![f64::NAN, 40.]).samples, 2);
+    }
+
+    #[test]
+    fn nights_are_attributed_to_the_day_they_start() {
+        let date = NaiveDate::from_ymd(2021, 1, 15);
+        let timezone = chrono_tz::America::New_York;
+        let at = |date: NaiveDate, hour| timezone.from_local_datetime(&date.and_hms(hour, 30, 0)).unwrap().timestamp_millis();
+        let measurements: Vec<Measurement> = [(at(date, 12), 70.), (at(date, 22), 30.), (at(date.succ(), 3), 40.), (at(date.succ(), 7), 60.)]
+            .iter()
+            .map(|&(timestamp, sound)| serde_json::from_value(json!({ "timestamp": timestamp, "sound": sound })).unwrap())
+            .collect();
+        let report = report(&measurements, timezone);
+        assert_eq!(report.hourly.len(), 4);
+        assert_eq!(report.nights.len(), 1);
+        let night = &report.nights[0];
+        assert_eq!(night.timestamp, at(date, 22) - 30 * 60 * 1000);
+        assert_eq!((night.samples, night.lmax), (2, 40.));
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use airq::{Data11, Data14, FilePath, Status};
+use chrono::{Datelike, TimeZone, Utc};
+use futures::stream;
+
+use crate::fetch_data::Error;
+use crate::storage::MeasurementStorage;
+
+/// device id the synthetic measurements are stored with
+const DEVICE: &str = "benchmark";
+/// the airQ stores a measurement every 2 minutes
+const INTERVAL_MILLIS: u64 = 2 * 60 * 1000;
+/// start of the synthetic history, 2020-01-01
+const START_MILLIS: u64 = 1_577_836_800_000;
+
+/// Stores a synthetic backfill of `files` files with `entries_per_file` measurements each,
+/// prints the throughput and returns the number of stored rows.
+///
+/// The measurements are stored for the device `benchmark` and aren't deleted afterwards,
+/// so this should be run against a scratch database.
+pub async fn backfill(storage: &dyn MeasurementStorage, files: u64, entries_per_file: u64) -> Result<u64, Error> {
+    let data: Vec<_> = (0..files)
+        .map(|file| {
+            let first = START_MILLIS + file * entries_per_file * INTERVAL_MILLIS;
+            let date = Utc.timestamp_millis(first as i64);
+            let path = FilePath {
+                year: date.year() as u16,
+                month: date.month() as u8,
+                day: date.day() as u8,
+                timestamp: first / 1000,
+            };
+            let entries = (0..entries_per_file).map(|i| entry(first + i * INTERVAL_MILLIS)).collect();
+            (path, entries)
+        })
+        .collect();
+
+    let start = Instant::now();
+    let rows = storage.store_entries(DEVICE, &mut stream::iter(data), None).await?;
+    let elapsed = start.elapsed();
+    println!(
+        "stored {} rows of {} files in {:.2}s, {:.0} rows/s",
+        rows, files, elapsed.as_secs_f64(), rows as f64 / elapsed.as_secs_f64(),
+    );
+    Ok(rows)
+}
+
+/// Returns a plausible readout at the given time following a daily cycle.
+fn entry(timestamp: u64) -> Data14 {
+    let day = (timestamp % (24 * 60 * 60 * 1000)) as f64 / (24. * 60. * 60. * 1000.);
+    let cycle = (day * 2. * std::f64::consts::PI).sin();
+    let value = |base: f64, amplitude: f64| [base + amplitude * cycle, amplitude / 10.];
+    Data14 {
+        data11: Data11 {
+            deviceid: DEVICE.to_string(),
+            status: Status::Ok("OK".to_string()),
+            uptime: (timestamp - START_MILLIS) / 1000,
+            health: 900. + 50. * cycle,
+            performance: 800. + 50. * cycle,
+            measuretime: 2000.,
+            timestamp,
+            bat: None,
+            door_event: None,
+            window_open: None,
+            tvoc: Some(value(200., 100.)),
+            humidity: value(45., 10.),
+            humidity_abs: value(8., 2.),
+            humidity_abs_delta: Some(0.),
+            temperature: value(21., 2.),
+            dewpt: value(9., 2.),
+            sound: value(40., 10.),
+            pressure: value(1013., 5.),
+            no2: Some(value(20., 10.)),
+            co: Some(value(0.5, 0.2)),
+            co2: value(800., 300.),
+            co2_delta: Some(0.),
+            pm1: value(5., 3.),
+            pm2_5: value(8., 4.),
+            pm10: value(12., 5.),
+            cnt0_3: value(800., 300.),
+            cnt0_5: value(250., 100.),
+            cnt1: value(40., 20.),
+            cnt2_5: value(5., 3.),
+            cnt5: value(1., 0.5),
+            cnt10: value(0.2, 0.1),
+            typ_ps: 0.6,
+            rest: HashMap::new(),
+        },
+        oxygen: value(20.9, 0.1),
+        o3: Some(value(30., 15.)),
+        so2: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::Sqlite;
+    use super::*;
+
+    #[rocket::async_test]
+    async fn backfill_stores_every_file() {
+        let path = std::env::temp_dir().join(format!("airq-benchmark-{}.sqlite", std::process::id()));
+        let sqlite = Sqlite::open(&path.display().to_string()).await.unwrap();
+        assert_eq!(backfill(&sqlite, 3, 5).await.unwrap(), 15);
+
+        let timestamps = sqlite.timestamps(DEVICE).await.unwrap();
+        assert_eq!(timestamps.first, Some(START_MILLIS as i64));
+        assert_eq!(timestamps.last, Some((START_MILLIS + 14 * INTERVAL_MILLIS) as i64));
+        // downloads continue in the last file
+        let (file, timestamp) = sqlite.last_timestamps(DEVICE).await.unwrap().unwrap();
+        assert_eq!((file.year, file.month, file.day), (2020, 1, 1));
+        assert_eq!(file.timestamp, (START_MILLIS + 10 * INTERVAL_MILLIS) / 1000);
+        assert_eq!(timestamp, START_MILLIS + 14 * INTERVAL_MILLIS);
+        // a second run stores nothing, the rows already exist
+        assert_eq!(backfill(&sqlite, 3, 5).await.unwrap(), 0);
+
+        drop(sqlite);
+        std::fs::remove_file(path).ok();
+    }
+}
@@ -0,0 +1,341 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use chrono::{Duration, Utc};
+use chrono_tz::Tz;
+use serde::Deserialize;
+
+use crate::room::Room;
+use crate::storage::RetentionCutoffs;
+
+/// default location of the config file, overridden by `AIRQ_CONFIG`
+const CONFIG_PATH: &str = "/etc/airq/config.toml";
+
+/// Server configuration read from a TOML file, see `config.example.toml`.
+///
+/// All settings can be overridden by environment variables, which were the only way to configure the server
+/// before the config file existed.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub storage: StorageConfig,
+    #[serde(rename = "device")]
+    pub devices: Vec<DeviceConfig>,
+    pub intervals: Intervals,
+    pub retention: Retention,
+    pub export: ExportConfig,
+    pub mqtt: Option<MqttConfig>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub address: Option<String>,
+    pub port: Option<u16>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum StorageConfig {
+    /// the last seven days in a JSON file, for local testing
+    Sevendays {
+        #[serde(default = "default_sevendays_path")]
+        path: String,
+    },
+    Postgres {
+        #[serde(default)]
+        url: String,
+        /// store the measurements in a TimescaleDB hypertable with an hourly continuous aggregate
+        #[serde(default)]
+        timescale: bool,
+        /// name of the device the measurements from before multiple devices were supported belong to,
+        /// the only device if not set
+        legacy_device: Option<String>,
+    },
+    Sqlite {
+        #[serde(default = "default_sqlite_path")]
+        path: String,
+    },
+    Influxdb(InfluxDbConfig),
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct InfluxDbConfig {
+    #[serde(default)]
+    pub url: String,
+    #[serde(default = "default_influxdb_database")]
+    pub database: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    pub name: String,
+    /// the device is searched via mDNS if not set
+    pub address: Option<String>,
+    #[serde(default)]
+    pub password: String,
+    #[serde(default)]
+    pub room: Room,
+}
+
+/// Poll intervals in seconds.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Intervals {
+    /// readout of the current values
+    pub current: u64,
+    /// download of the measurements stored on the airQ
+    pub data: u64,
+    /// mDNS discovery while the airQ isn't reachable
+    pub discover: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Retention {
+    /// days after which manual readouts are deleted
+    pub manual_readouts: u64,
+    /// days after which the downloaded measurements are downsampled, kept at full resolution if not set
+    pub measurements: Option<u64>,
+    /// bucket size of the downsampled measurements in minutes
+    pub downsample: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExportConfig {
+    /// time zone of the exported times if the request doesn't select one, e.g. `Europe/Berlin`
+    pub timezone: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// prefix of the state topics
+    #[serde(default = "default_mqtt_prefix")]
+    pub prefix: String,
+    #[serde(default = "default_mqtt_discovery_prefix")]
+    pub discovery_prefix: String,
+}
+
+fn default_sevendays_path() -> String { "sevendays.json".to_string() }
+fn default_sqlite_path() -> String { "airq.db".to_string() }
+fn default_influxdb_database() -> String { "airq".to_string() }
+fn default_mqtt_port() -> u16 { 1883 }
+fn default_mqtt_prefix() -> String { "airq".to_string() }
+fn default_mqtt_discovery_prefix() -> String { "homeassistant".to_string() }
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        if cfg!(debug_assertions) {
+            StorageConfig::Sevendays { path: default_sevendays_path() }
+        } else {
+            StorageConfig::Postgres { url: String::new(), timescale: false, legacy_device: None }
+        }
+    }
+}
+
+impl StorageConfig {
+    fn backend(&self) -> &'static str {
+        match self {
+            StorageConfig::Sevendays { .. } => "sevendays",
+            StorageConfig::Postgres { .. } => "postgres",
+            StorageConfig::Sqlite { .. } => "sqlite",
+            StorageConfig::Influxdb(_) => "influxdb",
+        }
+    }
+}
+
+impl Default for Intervals {
+    fn default() -> Self {
+        Intervals { current: 5, data: 2 * 60, discover: 30 }
+    }
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention { manual_readouts: 7, measurements: None, downsample: 60 }
+    }
+}
+
+impl Retention {
+    /// Returns the cutoffs of the retention rules relative to now.
+    pub fn cutoffs(&self) -> RetentionCutoffs {
+        let now = Utc::now();
+        let days_ago = |days: u64| (now - Duration::days(days as i64)).timestamp_millis() as u64;
+        let downsample_millis = self.downsample * 60 * 1000;
+        RetentionCutoffs {
+            manual_readouts_before: days_ago(self.manual_readouts),
+            // aligned to the buckets so that no bucket is downsampled partially
+            downsample_before: self.measurements.map(|days| days_ago(days) / downsample_millis * downsample_millis),
+            downsample_millis,
+        }
+    }
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        ExportConfig { timezone: "UTC".to_string() }
+    }
+}
+
+impl ExportConfig {
+    pub fn timezone(&self) -> Tz {
+        self.timezone.parse().expect("the time zone is validated when loading the config")
+    }
+}
+
+impl DeviceConfig {
+    fn new(name: &str) -> DeviceConfig {
+        DeviceConfig { name: name.to_string(), address: None, password: String::new(), room: Room::default() }
+    }
+}
+
+impl Config {
+    /// Reads the config file at `AIRQ_CONFIG` or `/etc/airq/config.toml` if it exists
+    /// and applies the overrides from the environment.
+    pub fn load() -> Config {
+        let explicit_path = std::env::var("AIRQ_CONFIG").ok();
+        let path = explicit_path.as_deref().unwrap_or(CONFIG_PATH);
+        let mut config: Config = match std::fs::read_to_string(path) {
+            Ok(content) => toml::from_str(&content).unwrap_or_else(|e| panic!("invalid config {}: {}", path, e)),
+            Err(e) if explicit_path.is_some() => panic!("couldn't read config {}: {}", path, e),
+            Err(_) => Config::default(),
+        };
+        config.apply_env();
+        config.validate();
+        config
+    }
+
+    fn apply_env(&mut self) {
+        override_var(&mut self.server.address, "ROCKET_ADDRESS", Some);
+        override_var(&mut self.server.port, "ROCKET_PORT", Some);
+
+        // `STORAGE` only resets the storage settings if it selects a different backend than the config file
+        match std::env::var("STORAGE") {
+            Ok(backend) if backend != self.storage.backend() => self.storage = match backend.as_str() {
+                "sevendays" => StorageConfig::Sevendays { path: default_sevendays_path() },
+                "postgres" => StorageConfig::Postgres { url: String::new(), timescale: false, legacy_device: None },
+                "sqlite" => StorageConfig::Sqlite { path: default_sqlite_path() },
+                "influxdb" => StorageConfig::Influxdb(InfluxDbConfig { database: default_influxdb_database(), ..Default::default() }),
+                _ => panic!("unknown STORAGE {}, expected sevendays, postgres, sqlite or influxdb", backend),
+            },
+            _ => (),
+        }
+        match &mut self.storage {
+            StorageConfig::Sevendays { .. } => (),
+            StorageConfig::Postgres { url, timescale, legacy_device } => {
+                override_var(url, "DATABASE_URL", |url| url);
+                override_var(timescale, "TIMESCALE", |timescale| timescale);
+                override_var(legacy_device, "AIRQ_LEGACY_DEVICE", Some);
+            },
+            StorageConfig::Sqlite { path } => override_var(path, "SQLITE_PATH", |path| path),
+            StorageConfig::Influxdb(influxdb) => {
+                override_var(&mut influxdb.url, "INFLUXDB_URL", |url| url);
+                override_var(&mut influxdb.database, "INFLUXDB_DATABASE", |database| database);
+                override_var(&mut influxdb.username, "INFLUXDB_USERNAME", Some);
+                override_var(&mut influxdb.password, "INFLUXDB_PASSWORD", Some);
+            },
+        }
+
+        // `AIRQ_DEVICES` replaces the configured devices, keeping the settings of devices with the same name
+        if let Ok(names) = std::env::var("AIRQ_DEVICES") {
+            let mut configured = std::mem::take(&mut self.devices);
+            self.devices = names.split(',').map(str::trim).filter(|name| !name.is_empty())
+                .map(|name| match configured.iter().position(|device| device.name == name) {
+                    Some(index) => configured.swap_remove(index),
+                    None => DeviceConfig::new(name),
+                })
+                .collect();
+        }
+        if self.devices.is_empty() {
+            self.devices.push(DeviceConfig::new("airq"));
+        }
+        // the variables without the device name only apply to a single device,
+        // except for `AIRQ_PASSWORD` filling in the password of devices without one
+        let single = self.devices.len() == 1;
+        for device in &mut self.devices {
+            let name = device.name.clone();
+            let var = |key: &str| std::env::var(format!("AIRQ_{}_{}", name.to_uppercase(), key)).ok();
+            let generic = |key: &str| std::env::var(format!("AIRQ_{}", key)).ok();
+            if let Some(address) = var("ADDRESS").or_else(|| generic("ADDRESS").filter(|_| single)) {
+                device.address = Some(address);
+            }
+            let password = var("PASSWORD")
+                .or_else(|| generic("PASSWORD").filter(|_| single || device.password.is_empty()));
+            if let Some(password) = password {
+                device.password = password;
+            }
+            device.room.apply_env(&name, single);
+        }
+
+        override_var(&mut self.intervals.current, "AIRQ_CURRENT_INTERVAL", |interval| interval);
+        override_var(&mut self.intervals.data, "AIRQ_DATA_INTERVAL", |interval| interval);
+        override_var(&mut self.intervals.discover, "AIRQ_DISCOVER_INTERVAL", |interval| interval);
+        override_var(&mut self.retention.manual_readouts, "AIRQ_MANUAL_READOUT_RETENTION", |days| days);
+        override_var(&mut self.retention.measurements, "AIRQ_MEASUREMENT_RETENTION", Some);
+        override_var(&mut self.retention.downsample, "AIRQ_DOWNSAMPLE_MINUTES", |minutes| minutes);
+        override_var(&mut self.export.timezone, "AIRQ_EXPORT_TIMEZONE", |timezone| timezone);
+
+        if let Ok(host) = std::env::var("MQTT_HOST") {
+            let mqtt = self.mqtt.get_or_insert_with(|| MqttConfig {
+                host: String::new(),
+                port: default_mqtt_port(),
+                username: None,
+                password: None,
+                prefix: default_mqtt_prefix(),
+                discovery_prefix: default_mqtt_discovery_prefix(),
+            });
+            mqtt.host = host;
+        }
+        if let Some(mqtt) = &mut self.mqtt {
+            override_var(&mut mqtt.port, "MQTT_PORT", |port| port);
+            override_var(&mut mqtt.username, "MQTT_USERNAME", Some);
+            override_var(&mut mqtt.password, "MQTT_PASSWORD", Some);
+            override_var(&mut mqtt.prefix, "MQTT_PREFIX", |prefix| prefix);
+            override_var(&mut mqtt.discovery_prefix, "MQTT_DISCOVERY_PREFIX", |prefix| prefix);
+        }
+    }
+
+    fn validate(&self) {
+        match &self.storage {
+            StorageConfig::Postgres { url, .. } if url.is_empty() => panic!("no PostgreSQL url configured, set storage.url or DATABASE_URL"),
+            StorageConfig::Influxdb(influxdb) if influxdb.url.is_empty() => panic!("no InfluxDB url configured, set storage.url or INFLUXDB_URL"),
+            _ => (),
+        }
+        if let StorageConfig::Postgres { legacy_device: Some(legacy_device), .. } = &self.storage {
+            assert!(
+                self.devices.iter().any(|device| &device.name == legacy_device),
+                "storage.legacy_device {} isn't a configured device", legacy_device,
+            );
+        }
+        for device in &self.devices {
+            assert!(!device.password.is_empty(), "no password configured for airQ {}", device.name);
+        }
+        assert!(self.retention.downsample > 0, "retention.downsample must be at least a minute");
+        if let Err(e) = self.export.timezone.parse::<Tz>() {
+            panic!("invalid export.timezone: {}", e);
+        }
+        assert!(
+            self.devices.len() == 1 || self.devices.iter().all(|device| device.address.is_some()),
+            "mDNS discovery is only supported with a single airQ, configure the address of each device",
+        );
+    }
+}
+
+/// Overrides `value` with the parsed environment variable `name` if it is set.
+pub fn override_var<T: FromStr, U>(value: &mut U, name: &str, wrap: impl FnOnce(T) -> U) where T::Err: Display {
+    if let Ok(var) = std::env::var(name) {
+        let parsed = var.parse().unwrap_or_else(|e| panic!("invalid {}: {}", name, e));
+        *value = wrap(parsed);
+    }
+}
@@ -0,0 +1,84 @@
+use std::sync::{Arc, RwLock};
+
+use serde::Serialize;
+
+use crate::fetch_data::{Error, FetchData};
+use crate::config::DeviceConfig;
+use crate::room::Room;
+use crate::storage::MeasurementStorage;
+
+/// An airQ managed by the server.
+pub struct Device {
+    pub name: String,
+    /// configured address, the device is searched via mDNS if not set
+    pub address: Option<String>,
+    pub room: Room,
+    pub fetchdata: FetchData,
+    /// `Data11::deviceid` used as key in the storage, known once the device responded for the first time
+    id: RwLock<Option<String>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceInfo {
+    name: String,
+    id: Option<String>,
+    connected: bool,
+}
+
+impl Device {
+    pub fn id(&self) -> Option<String> {
+        self.id.read().unwrap().clone()
+    }
+
+    /// Remembers the id the device reported and stores the mapping from name to id in the storage.
+    pub async fn set_id(&self, id: &str, storage: &dyn MeasurementStorage) -> Result<(), Error> {
+        if self.id().as_deref() == Some(id) {
+            return Ok(());
+        }
+        storage.register_device(id, &self.name).await?;
+        *self.id.write().unwrap() = Some(id.to_string());
+        Ok(())
+    }
+
+    pub fn info(&self) -> DeviceInfo {
+        DeviceInfo {
+            name: self.name.clone(),
+            id: self.id(),
+            connected: self.fetchdata.is_connected() && self.fetchdata.failures() == 0,
+        }
+    }
+}
+
+pub struct Devices {
+    devices: Vec<Arc<Device>>,
+}
+
+impl Devices {
+    /// Creates the configured devices.
+    ///
+    /// Device ids already known to the storage are restored so that their history is available
+    /// even if they aren't reachable.
+    pub async fn new(configs: &[DeviceConfig], storage: &dyn MeasurementStorage) -> Result<Devices, Error> {
+        let known_ids = storage.device_ids().await?;
+        let devices = configs.iter()
+            .map(|config| Arc::new(Device {
+                name: config.name.clone(),
+                address: config.address.clone(),
+                room: config.room.clone(),
+                fetchdata: FetchData::new(&config.password),
+                id: RwLock::new(known_ids.get(&config.name).cloned()),
+            }))
+            .collect();
+        Ok(Devices { devices })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Device>> {
+        self.devices.iter()
+    }
+
+    /// Looks up a device by its name or id.
+    pub fn get(&self, name_or_id: &str) -> Option<&Arc<Device>> {
+        self.devices.iter()
+            .find(|device| device.name == name_or_id || device.id().as_deref() == Some(name_or_id))
+    }
+}
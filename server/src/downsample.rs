@@ -0,0 +1,295 @@
+//! Downsampling of time series which keeps the shape of the chart, unlike averaging into buckets.
+//!
+//! Points are `(timestamp, value)` in chronological order.
+
+/// A downsampling algorithm selecting at most the given number of points.
+pub type Downsample = fn(&[(f64, f64)], usize) -> Vec<(f64, f64)>;
+
+/// How `/data` reduces a range to the point budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Downsampling {
+    /// averages (or other aggregations) of buckets shared by all fields
+    Avg,
+    /// Largest-Triangle-Three-Buckets per field
+    Lttb,
+    /// first, last, minimum and maximum of each bucket per field
+    M4,
+}
+
+impl Downsampling {
+    pub fn parse(name: &str) -> Result<Downsampling, String> {
+        match name {
+            "avg" => Ok(Downsampling::Avg),
+            "lttb" => Ok(Downsampling::Lttb),
+            "m4" => Ok(Downsampling::M4),
+            name => Err(format!("unknown downsampling {:?}", name)),
+        }
+    }
+}
+
+/// Selects `threshold` points with the Largest-Triangle-Three-Buckets algorithm by Sveinn Steinarsson.
+///
+/// The first and last point are always kept, of each bucket in between the point forming
+/// the largest triangle with the previously selected point and the average of the next bucket.
+pub fn lttb(points: &[(f64, f64)], threshold: usize) -> Vec<(f64, f64)> {
+    if threshold >= points.len() {
+        return points.to_vec();
+    }
+    if threshold < 3 {
+        return endpoints(points, threshold);
+    }
+    let bucket_size = (points.len() - 2) as f64 / (threshold - 2) as f64;
+    let bucket_start = |bucket: usize| ((bucket as f64 * bucket_size) as usize + 1).min(points.len() - 1);
+
+    let mut sampled = Vec::with_capacity(threshold);
+    sampled.push(points[0]);
+    let mut previous = 0;
+    for bucket in 0..threshold - 2 {
+        let (start, end) = (bucket_start(bucket), bucket_start(bucket + 1));
+        // the next bucket of the last bucket is the last point
+        let next = &points[end..bucket_start(bucket + 2).max(end + 1).min(points.len())];
+        let next_x = next.iter().map(|&(x, _)| x).sum::<f64>() / next.len() as f64;
+        let next_y = next.iter().map(|&(_, y)| y).sum::<f64>() / next.len() as f64;
+        let (previous_x, previous_y) = points[previous];
+        let area = |&(x, y): &(f64, f64)| ((previous_x - next_x) * (y - previous_y) - (previous_x - x) * (next_y - previous_y)).abs();
+        previous = (start..end.max(start + 1))
+            .max_by(|&a, &b| area(&points[a]).total_cmp(&area(&points[b])))
+            .unwrap();
+        sampled.push(points[previous]);
+    }
+    sampled.push(points[points.len() - 1]);
+    sampled
+}
+
+/// Keeps the first, last, minimum and maximum point of `threshold / 4` buckets of equal duration,
+/// the M4 aggregation by Jugel et al., so that a line chart of that many pixels wide looks the same.
+pub fn m4(points: &[(f64, f64)], threshold: usize) -> Vec<(f64, f64)> {
+    if threshold >= points.len() {
+        return points.to_vec();
+    }
+    let buckets = threshold / 4;
+    let (first, last) = (points[0].0, points[points.len() - 1].0);
+    let width = (last - first) / buckets as f64;
+    if buckets == 0 || width <= 0. {
+        return endpoints(points, threshold);
+    }
+    let bucket = |&(x, _): &(f64, f64)| (((x - first) / width) as usize).min(buckets - 1);
+
+    let mut sampled = Vec::with_capacity(threshold);
+    let mut start = 0;
+    while start < points.len() {
+        let current = bucket(&points[start]);
+        let end = start + points[start..].iter().take_while(|point| bucket(point) == current).count();
+        let points = &points[start..end];
+        let by_value = |&a: &usize, &b: &usize| points[a].1.total_cmp(&points[b].1);
+        let min = (0..points.len()).min_by(by_value).unwrap();
+        let max = (0..points.len()).max_by(by_value).unwrap();
+        let mut indices = vec![0, min, max, points.len() - 1];
+        indices.sort_unstable();
+        indices.dedup();
+        sampled.extend(indices.into_iter().map(|i| points[i]));
+        start = end;
+    }
+    sampled
+}
+
+/// Keeps the first and last point if the threshold is too small for anything else.
+fn endpoints(points: &[(f64, f64)], threshold: usize) -> Vec<(f64, f64)> {
+    match threshold {
+        0 => Vec::new(),
+        1 => vec![points[0]],
+        _ => vec![points[0], points[points.len() - 1]],
+    }
+}
+
+/// Returns the first segment of the merged segments `segment` belongs to.
+fn find(group: &mut [usize], segment: usize) -> usize {
+    let mut root = segment;
+    while group[root] != root {
+        root = group[root];
+    }
+    group[segment] = root;
+    root
+}
+
+/// Downsamples the segments between points with a `NaN` value separately, so that gaps stay gaps,
+/// allotting the `threshold` to the segments by their number of points.
+/// A single point with `NaN` is kept between the segments and counts towards the `threshold`.
+///
+/// If there are too many segments to keep at least their endpoints, the segments separated by the
+/// shortest gaps are merged until they fit, so that the result never exceeds the `threshold`.
+pub fn segmented(points: &[(f64, f64)], threshold: usize, downsample: Downsample) -> Vec<(f64, f64)> {
+    // the segments and the first point of the gap after each segment but the last
+    let mut segments = Vec::new();
+    let mut gaps = Vec::new();
+    let mut start = 0;
+    while start < points.len() {
+        if points[start].1.is_nan() {
+            start += 1;
+            continue;
+        }
+        let end = start + points[start..].iter().take_while(|(_, y)| !y.is_nan()).count();
+        if !segments.is_empty() {
+            let gap_start = start - points[..start].iter().rev().take_while(|(_, y)| y.is_nan()).count();
+            gaps.push(points[gap_start]);
+        }
+        segments.push(&points[start..end]);
+        start = end;
+    }
+    if segments.is_empty() {
+        return Vec::new();
+    }
+
+    // each segment needs at least its endpoints and each gap a point,
+    // remove the shortest gaps by merging their segments until that fits into the threshold
+    let mut group: Vec<_> = (0..segments.len()).collect();
+    let mut group_len: Vec<_> = segments.iter().map(|segment| segment.len()).collect();
+    let mut kept = vec![true; gaps.len()];
+    let mut required = gaps.len() + group_len.iter().map(|&len| len.min(2)).sum::<usize>();
+    let mut by_duration: Vec<_> = (0..gaps.len()).collect();
+    by_duration.sort_by(|&a, &b| {
+        let duration = |gap: usize| segments[gap + 1][0].0 - segments[gap][segments[gap].len() - 1].0;
+        duration(a).total_cmp(&duration(b))
+    });
+    for gap in by_duration {
+        if required <= threshold {
+            break;
+        }
+        let (left, right) = (find(&mut group, gap), find(&mut group, gap + 1));
+        let (left_len, right_len) = (group_len[left], group_len[right]);
+        required = required + (left_len + right_len).min(2) - left_len.min(2) - right_len.min(2) - 1;
+        group[right] = left;
+        group_len[left] = left_len + right_len;
+        kept[gap] = false;
+    }
+
+    // allot the rest of the threshold by the number of points beyond the endpoints
+    let merged: Vec<Vec<(f64, f64)>> = {
+        let mut merged = vec![segments[0].to_vec()];
+        for (gap, segment) in kept.iter().zip(&segments[1..]) {
+            if *gap {
+                merged.push(segment.to_vec());
+            } else {
+                merged.last_mut().unwrap().extend_from_slice(segment);
+            }
+        }
+        merged
+    };
+    let available = threshold.saturating_sub(merged.len() - 1);
+    let minimum: usize = merged.iter().map(|segment| segment.len().min(2)).sum();
+    let extra = available.saturating_sub(minimum);
+    let remaining: usize = merged.iter().map(|segment| segment.len() - segment.len().min(2)).sum();
+
+    let mut sampled = Vec::new();
+    let mut gaps = kept.iter().zip(gaps).filter(|(kept, _)| **kept).map(|(_, gap)| gap);
+    for (i, segment) in merged.iter().enumerate() {
+        if i > 0 {
+            sampled.push(gaps.next().unwrap());
+        }
+        let base = segment.len().min(2);
+        let budget = match remaining {
+            0 => base,
+            remaining => base + extra * (segment.len() - base) / remaining,
+        };
+        sampled.extend(downsample(segment, budget.min(available)));
+    }
+    sampled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(len: usize) -> Vec<(f64, f64)> {
+        (0..len).map(|x| (x as f64, (x % 7) as f64)).collect()
+    }
+
+    /// `segments` segments of `len` points one apart, separated by a gap marker and a gap of `gap` growing with the segment
+    fn with_gaps(segments: usize, len: usize, gap: impl Fn(usize) -> f64) -> Vec<(f64, f64)> {
+        let mut points = Vec::new();
+        let mut x = 0.;
+        for segment in 0..segments {
+            if segment > 0 {
+                points.push((x, f64::NAN));
+                x += gap(segment);
+            }
+            for i in 0..len {
+                points.push((x, ((segment + i) % 5) as f64));
+                x += 1.;
+            }
+        }
+        points
+    }
+
+    #[test]
+    fn lttb_keeps_endpoints_and_peaks() {
+        let mut points = line(1000);
+        points[500].1 = 100.;
+        let sampled = lttb(&points, 50);
+        assert_eq!(sampled.len(), 50);
+        assert_eq!(sampled[0], points[0]);
+        assert_eq!(sampled[49], points[999]);
+        assert!(sampled.contains(&(500., 100.)));
+        assert!(sampled.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    }
+
+    #[test]
+    fn m4_keeps_extremes_of_each_bucket() {
+        let mut points = line(1000);
+        points[123].1 = -50.;
+        points[877].1 = 80.;
+        let sampled = m4(&points, 40);
+        assert!(sampled.len() <= 40);
+        assert!(sampled.contains(&(123., -50.)));
+        assert!(sampled.contains(&(877., 80.)));
+        assert_eq!(sampled[0], points[0]);
+        assert_eq!(sampled[sampled.len() - 1], points[999]);
+    }
+
+    #[test]
+    fn small_thresholds() {
+        let points = line(100);
+        for downsample in [lttb, m4] {
+            assert_eq!(downsample(&points, 0), vec![]);
+            assert_eq!(downsample(&points, 1), vec![points[0]]);
+            assert_eq!(downsample(&points, 2), vec![points[0], points[99]]);
+            assert!(downsample(&points, 3).len() <= 3);
+            assert_eq!(downsample(&points, 100), points);
+            assert_eq!(downsample(&[], 10), vec![]);
+        }
+    }
+
+    #[test]
+    fn segmented_keeps_everything_within_threshold() {
+        let points = with_gaps(3, 5, |_| 10.);
+        for downsample in [lttb, m4] {
+            assert_eq!(format!("{:?}", segmented(&points, 17, downsample)), format!("{:?}", points));
+        }
+    }
+
+    #[test]
+    fn segmented_respects_threshold_with_many_gaps() {
+        // e.g. two weeks with hourly gaps
+        let points = with_gaps(336, 25, |segment| 10. + segment as f64);
+        for downsample in [lttb, m4] {
+            for threshold in [0, 1, 2, 3, 10, 100, 500, 1000, 5000] {
+                let sampled = segmented(&points, threshold, downsample);
+                assert!(sampled.len() <= threshold, "{} points for a threshold of {}", sampled.len(), threshold);
+                if let (Some(first), Some(last)) = (sampled.first(), sampled.last()) {
+                    assert!(!first.1.is_nan() && !last.1.is_nan());
+                }
+                assert!(sampled.windows(2).all(|pair| !pair[0].1.is_nan() || !pair[1].1.is_nan()));
+            }
+        }
+    }
+
+    #[test]
+    fn segmented_keeps_the_longest_gaps() {
+        // the gap after the 5th segment is the longest
+        let points = with_gaps(10, 50, |segment| if segment == 5 { 1000. } else { 10. + segment as f64 });
+        let sampled = segmented(&points, 20, lttb);
+        assert!(sampled.len() <= 20);
+        let gaps: Vec<_> = sampled.iter().filter(|(_, y)| y.is_nan()).map(|&(x, _)| x).collect();
+        assert!(gaps.contains(&points[5 * 51 - 1].0), "{:?}", gaps);
+    }
+}
@@ -0,0 +1,337 @@
+//! Export of measurements as CSV or Parquet files for analysis, e.g. with pandas or Excel.
+
+use std::fs::{File, OpenOptions};
+use std::io::SeekFrom;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chrono::{DateTime, NaiveDate, SecondsFormat, TimeZone};
+use chrono_tz::Tz;
+use parquet::basic::{ConvertedType, Repetition, Type as PhysicalType};
+use parquet::column::writer::ColumnWriter;
+use parquet::data_type::ByteArray;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::types::Type;
+use rocket::http::{ContentType, Header};
+use rocket::response::Stream;
+use serde_json::{Map, Value};
+use tokio::io::{self, AsyncSeekExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::sync::mpsc;
+
+use crate::Measurement;
+use crate::fetch_data::Error;
+use crate::storage::{Aggregation, MeasurementStorage};
+
+/// the exported fields if none are selected, in the order of their columns
+pub const DEFAULT_FIELDS: [&str; 32] = [
+    "health", "performance", "tvoc", "humidity", "humidity_abs", "temperature", "dewpt", "sound", "pressure", "no2",
+    "co", "co2", "pm1", "pm2_5", "pm10", "oxygen", "o3", "so2", "uptime", "measuretime",
+    "bat", "door_event", "window_open", "humidity_abs_delta", "co2_delta", "cnt0_3", "cnt0_5", "cnt1", "cnt2_5", "cnt5",
+    "cnt10", "typ_ps",
+];
+/// rows per row group of Parquet files
+const ROW_GROUP_SIZE: usize = 10_000;
+
+/// number of Parquet exports so far, to name their temporary files
+static PARQUET_EXPORTS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    Parquet,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Result<Format, String> {
+        match name {
+            "csv" => Ok(Format::Csv),
+            "parquet" => Ok(Format::Parquet),
+            name => Err(format!("unknown format {:?}, expected csv or parquet", name)),
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Parquet => "parquet",
+        }
+    }
+
+    fn content_type(self) -> ContentType {
+        match self {
+            Format::Csv => ContentType::CSV,
+            Format::Parquet => ContentType::new("application", "vnd.apache.parquet"),
+        }
+    }
+}
+
+/// Columns of an export: the time, then the value and optionally the uncertainty of each field.
+pub struct Columns {
+    fields: Vec<String>,
+    /// add a column `<field>_uncertainty` after each field
+    uncertainty: bool,
+    /// time zone of the ISO 8601 times
+    timezone: Tz,
+}
+
+/// A row of an export with the values in the order of the columns after the time.
+struct Row {
+    time: String,
+    values: Vec<Option<f64>>,
+}
+
+impl Columns {
+    /// Only the fields in `rest` sent as `[value, uncertainty]` have an uncertainty,
+    /// the uncertainties of the fields known to the server aren't stored.
+    pub fn new(fields: Vec<String>, uncertainty: bool, timezone: Tz) -> Result<Columns, String> {
+        if uncertainty {
+            let known: Vec<_> = fields.iter().filter(|field| DEFAULT_FIELDS.contains(&field.as_str())).map(String::as_str).collect();
+            if !known.is_empty() {
+                return Err(format!(
+                    "the uncertainties of {} aren't stored, uncertainty is only available for fields unknown to the server",
+                    known.join(", "),
+                ));
+            }
+        }
+        Ok(Columns { fields, uncertainty, timezone })
+    }
+
+    fn names(&self) -> Vec<String> {
+        let mut names = vec!["time".to_string()];
+        for field in &self.fields {
+            names.push(field.clone());
+            if self.uncertainty {
+                names.push(format!("{}_uncertainty", field));
+            }
+        }
+        names
+    }
+
+    fn row(&self, measurement: &Measurement) -> Row {
+        let fields = flat_fields(measurement);
+        let time = measurement.timestamp
+            .map(|timestamp| self.timezone.timestamp_millis(timestamp).to_rfc3339_opts(SecondsFormat::Millis, false))
+            .unwrap_or_default();
+        let mut values = Vec::new();
+        for field in &self.fields {
+            let (value, uncertainty) = match fields.get(field) {
+                Some(Value::Array(pair)) => (pair.get(0).and_then(Value::as_f64), pair.get(1).and_then(Value::as_f64)),
+                Some(value) => (value.as_f64(), None),
+                None => (None, None),
+            };
+            values.push(value);
+            if self.uncertainty {
+                values.push(uncertainty);
+            }
+        }
+        Row { time, values }
+    }
+}
+
+/// Returns the fields of the measurement with the fields of `rest` at the top level.
+pub fn flat_fields(measurement: &Measurement) -> Map<String, Value> {
+    let mut fields = match serde_json::to_value(measurement) {
+        Ok(Value::Object(fields)) => fields,
+        _ => unreachable!("measurements are serialized as objects"),
+    };
+    if let Some(Value::Object(rest)) = fields.remove("rest") {
+        fields.extend(rest);
+    }
+    fields
+}
+
+/// Parses a time given in ms since the epoch, as RFC 3339 or as a date, which is midnight in `timezone`.
+pub fn parse_time(time: &str, timezone: Tz) -> Result<u64, String> {
+    if let Ok(millis) = time.parse() {
+        return Ok(millis);
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+        return Ok(time.timestamp_millis() as u64);
+    }
+    NaiveDate::parse_from_str(time, "%Y-%m-%d").ok()
+        .and_then(|date| timezone.from_local_datetime(&date.and_hms(0, 0, 0)).earliest())
+        .map(|time| time.timestamp_millis() as u64)
+        .ok_or_else(|| format!("invalid time {:?}, expected ms since the epoch, RFC 3339 or YYYY-MM-DD", time))
+}
+
+/// Parses `raw` or a bucket size like `30s`, `15m`, `1h` or `1d` into ms, `None` for `raw`.
+pub fn parse_resolution(resolution: &str) -> Result<Option<u64>, String> {
+    if resolution == "raw" {
+        return Ok(None);
+    }
+    let invalid = || format!("invalid resolution {:?}, expected raw or e.g. 30s, 15m, 1h or 1d", resolution);
+    let unit = match resolution.chars().last() {
+        Some('s') => 1000,
+        Some('m') => 60 * 1000,
+        Some('h') => 60 * 60 * 1000,
+        Some('d') => 24 * 60 * 60 * 1000,
+        _ => return Err(invalid()),
+    };
+    match resolution[..resolution.len() - 1].parse::<u64>() {
+        Ok(count) if count > 0 => Ok(Some(count * unit)),
+        _ => Err(invalid()),
+    }
+}
+
+/// The file of an export, which is written while it is sent.
+#[derive(rocket::Responder)]
+pub struct ExportFile {
+    file: Stream<DuplexStream>,
+    content_type: ContentType,
+    disposition: Header<'static>,
+}
+
+/// Exports the measurements of the device in the range, combined into buckets of `resolution` ms if given.
+///
+/// CSV files are written by a separate task while they are sent, errors after the response started
+/// can't be reported anymore and end the file early.
+/// Parquet files are written completely before they are sent, so that errors are returned.
+#[allow(clippy::too_many_arguments)]
+pub async fn export(
+    storage: Arc<dyn MeasurementStorage>, device: String, first: u64, last: u64, resolution: Option<u64>,
+    columns: Columns, format: Format, filename: &str,
+) -> Result<ExportFile, Error> {
+    let (mut writer, reader) = io::duplex(64 * 1024);
+    let disposition = Header::new("Content-Disposition", format!("attachment; filename=\"{}.{}\"", filename, format.extension()));
+    let (sender, receiver) = mpsc::channel(1024);
+    let fields = columns.fields.clone();
+    let query = async move {
+        match resolution {
+            None => storage.raw_rows(&device, first, last, Some(&fields), sender).await?,
+            Some(millis) => {
+                let combine_datapoints = (millis / (2 * 60 * 1000)).max(1);
+                let measurements = storage.aggregate(&device, first, last, combine_datapoints, millis, &[Aggregation::Avg], Some(&fields)).await?
+                    .pop().unwrap_or_default();
+                for measurement in measurements {
+                    if sender.send(measurement).await.is_err() {
+                        break;
+                    }
+                }
+            },
+        }
+        Ok::<_, Error>(device)
+    };
+    match format {
+        Format::Csv => {
+            // the receiver is dropped when the client disconnects, which stops the query
+            tokio::spawn(async move {
+                match futures::join!(query, write_csv(&columns, receiver, &mut writer)) {
+                    (Err(e), _) => eprintln!("Error querying export: {:?}", e),
+                    (Ok(device), Err(e)) => eprintln!("Error writing export of {}: {:?}", device, e),
+                    (Ok(_), Ok(())) => (),
+                }
+            });
+        },
+        Format::Parquet => {
+            let (queried, file) = futures::join!(query, write_parquet(columns, receiver));
+            let device = queried?;
+            let mut file = file?;
+            tokio::spawn(async move {
+                if let Err(e) = io::copy(&mut file, &mut writer).await {
+                    eprintln!("Error sending export of {}: {:?}", device, e);
+                }
+            });
+        },
+    }
+    Ok(ExportFile { file: Stream::from(reader), content_type: format.content_type(), disposition })
+}
+
+async fn write_csv(columns: &Columns, mut rows: mpsc::Receiver<Measurement>, out: &mut (impl AsyncWrite + Unpin)) -> Result<(), Error> {
+    let header: Vec<_> = columns.names().iter().map(|name| csv_field(name)).collect();
+    out.write_all(format!("{}\r\n", header.join(",")).as_bytes()).await?;
+    while let Some(measurement) = rows.recv().await {
+        let row = columns.row(&measurement);
+        let mut line = row.time;
+        for value in row.values {
+            line.push(',');
+            if let Some(value) = value {
+                line.push_str(&value.to_string());
+            }
+        }
+        line.push_str("\r\n");
+        out.write_all(line.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Quotes a CSV field if needed.
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\r', '\n'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Writes the Parquet file to a temporary file, because its metadata is at the end,
+/// and returns it positioned at the start.
+async fn write_parquet(columns: Columns, rows: mpsc::Receiver<Measurement>) -> Result<tokio::fs::File, Error> {
+    let path = std::env::temp_dir().join(format!(
+        "airq-export-{}-{}.parquet", std::process::id(), PARQUET_EXPORTS.fetch_add(1, Ordering::Relaxed),
+    ));
+    let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+    // the file is deleted once it is closed, even if the export fails
+    std::fs::remove_file(&path)?;
+    let parquet_file = file.try_clone()?;
+    tokio::task::spawn_blocking(move || write_parquet_file(&columns, rows, parquet_file)).await
+        .expect("writing the parquet file panicked")?;
+
+    let mut file = tokio::fs::File::from_std(file);
+    file.seek(SeekFrom::Start(0)).await?;
+    Ok(file)
+}
+
+/// Writes the rows in row groups of `ROW_GROUP_SIZE` with the time as string and all other columns as optional doubles.
+fn write_parquet_file(columns: &Columns, mut rows: mpsc::Receiver<Measurement>, file: File) -> Result<(), Error> {
+    let mut fields = vec![Arc::new(
+        Type::primitive_type_builder("time", PhysicalType::BYTE_ARRAY)
+            .with_repetition(Repetition::REQUIRED)
+            .with_converted_type(ConvertedType::UTF8)
+            .build()?,
+    )];
+    for name in columns.names().iter().skip(1) {
+        fields.push(Arc::new(
+            Type::primitive_type_builder(name, PhysicalType::DOUBLE)
+                .with_repetition(Repetition::OPTIONAL)
+                .build()?,
+        ));
+    }
+    let schema = Arc::new(Type::group_type_builder("measurements").with_fields(&mut fields).build()?);
+    let mut writer = SerializedFileWriter::new(file, schema, Arc::new(WriterProperties::builder().build()))?;
+
+    loop {
+        let mut group = Vec::with_capacity(ROW_GROUP_SIZE);
+        while group.len() < ROW_GROUP_SIZE {
+            match rows.blocking_recv() {
+                Some(measurement) => group.push(columns.row(&measurement)),
+                None => break,
+            }
+        }
+        if group.is_empty() {
+            break;
+        }
+        let mut row_group = writer.next_row_group()?;
+        let mut column = 0;
+        while let Some(mut column_writer) = row_group.next_column()? {
+            match column_writer.untyped() {
+                ColumnWriter::ByteArrayColumnWriter(times) => {
+                    let values: Vec<_> = group.iter().map(|row| ByteArray::from(row.time.as_str())).collect();
+                    times.write_batch(&values, None, None)?;
+                },
+                ColumnWriter::DoubleColumnWriter(doubles) => {
+                    // the definition level is 0 for missing values, which are left out of the values
+                    let values: Vec<_> = group.iter().filter_map(|row| row.values[column - 1]).collect();
+                    let definitions: Vec<_> = group.iter().map(|row| row.values[column - 1].is_some() as i16).collect();
+                    doubles.write_batch(&values, Some(&definitions), None)?;
+                },
+                _ => unreachable!("only the time and doubles are exported"),
+            }
+            column_writer.close()?;
+            column += 1;
+        }
+        row_group.close()?;
+    }
+    writer.close()?;
+    Ok(())
+}
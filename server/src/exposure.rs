@@ -0,0 +1,268 @@
+use std::collections::BTreeMap;
+
+use chrono::{NaiveDate, TimeZone};
+use chrono_tz::Tz;
+use serde::Serialize;
+
+use crate::Measurement;
+
+const HOUR_MILLIS: i64 = 60 * 60 * 1000;
+/// number of worst periods reported per guideline
+const NUM_WORST: usize = 5;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub enum Standard {
+    #[serde(rename = "WHO 2021")]
+    Who2021,
+    #[serde(rename = "EU")]
+    Eu,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Averaging {
+    /// maximum of the rolling means over the given number of hours within a day
+    Hours(u32),
+    /// mean of a calendar day
+    Day,
+    /// mean of the whole requested range as an estimate of the annual mean
+    Year,
+}
+
+pub struct Guideline {
+    pub pollutant: &'static str,
+    pub standard: Standard,
+    pub averaging: Averaging,
+    /// limit in the unit of the measurement (μg/m³, CO in mg/m³)
+    pub limit: f64,
+    value: fn(&Measurement) -> Option<f64>,
+}
+
+pub const GUIDELINES: &[Guideline] = &[
+    Guideline { pollutant: "pm2_5", standard: Standard::Who2021, averaging: Averaging::Day, limit: 15., value: |m| m.pm2_5 },
+    Guideline { pollutant: "pm2_5", standard: Standard::Who2021, averaging: Averaging::Year, limit: 5., value: |m| m.pm2_5 },
+    Guideline { pollutant: "pm2_5", standard: Standard::Eu, averaging: Averaging::Year, limit: 25., value: |m| m.pm2_5 },
+    Guideline { pollutant: "pm10", standard: Standard::Who2021, averaging: Averaging::Day, limit: 45., value: |m| m.pm10 },
+    Guideline { pollutant: "pm10", standard: Standard::Who2021, averaging: Averaging::Year, limit: 15., value: |m| m.pm10 },
+    Guideline { pollutant: "pm10", standard: Standard::Eu, averaging: Averaging::Day, limit: 50., value: |m| m.pm10 },
+    Guideline { pollutant: "pm10", standard: Standard::Eu, averaging: Averaging::Year, limit: 40., value: |m| m.pm10 },
+    Guideline { pollutant: "no2", standard: Standard::Who2021, averaging: Averaging::Day, limit: 25., value: |m| m.no2 },
+    Guideline { pollutant: "no2", standard: Standard::Who2021, averaging: Averaging::Year, limit: 10., value: |m| m.no2 },
+    Guideline { pollutant: "no2", standard: Standard::Eu, averaging: Averaging::Hours(1), limit: 200., value: |m| m.no2 },
+    Guideline { pollutant: "no2", standard: Standard::Eu, averaging: Averaging::Year, limit: 40., value: |m| m.no2 },
+    Guideline { pollutant: "o3", standard: Standard::Who2021, averaging: Averaging::Hours(8), limit: 100., value: |m| m.o3 },
+    Guideline { pollutant: "o3", standard: Standard::Eu, averaging: Averaging::Hours(8), limit: 120., value: |m| m.o3 },
+    Guideline { pollutant: "co", standard: Standard::Who2021, averaging: Averaging::Day, limit: 4., value: |m| m.co },
+    Guideline { pollutant: "co", standard: Standard::Eu, averaging: Averaging::Hours(8), limit: 10., value: |m| m.co },
+    Guideline { pollutant: "so2", standard: Standard::Who2021, averaging: Averaging::Day, limit: 40., value: |m| m.so2 },
+    Guideline { pollutant: "so2", standard: Standard::Eu, averaging: Averaging::Hours(1), limit: 350., value: |m| m.so2 },
+    Guideline { pollutant: "so2", standard: Standard::Eu, averaging: Averaging::Day, limit: 125., value: |m| m.so2 },
+];
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Period {
+    from: i64,
+    to: i64,
+    /// time-weighted average over the period
+    value: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GuidelineReport {
+    pollutant: &'static str,
+    standard: Standard,
+    averaging: Averaging,
+    limit: f64,
+    /// number of days with enough data to evaluate the guideline
+    days: usize,
+    /// number of days on which the limit was exceeded, `None` for annual means
+    exceedance_days: Option<usize>,
+    /// periods with the highest averages, not overlapping each other
+    worst: Vec<Period>,
+}
+
+/// Evaluates all guidelines against hourly averages, with days in the given time zone.
+pub fn report(hourly: &[Measurement], timezone: Tz) -> Vec<GuidelineReport> {
+    GUIDELINES.iter()
+        .map(|guideline| {
+            let values: Vec<_> = hourly.iter()
+                .filter_map(|m| Some((m.timestamp?, (guideline.value)(m)?)))
+                .filter(|(_, value)| value.is_finite())
+                .collect();
+            evaluate(guideline, &values, timezone)
+        })
+        .collect()
+}
+
+fn evaluate(guideline: &Guideline, values: &[(i64, f64)], timezone: Tz) -> GuidelineReport {
+    let periods = match guideline.averaging {
+        Averaging::Hours(hours) => rolling_means(values, hours),
+        Averaging::Day => daily_means(values, timezone),
+        Averaging::Year => {
+            let (days, worst) = match (values.first(), values.last()) {
+                (Some(&(from, _)), Some(&(to, _))) => {
+                    let value = values.iter().map(|&(_, value)| value).sum::<f64>() / values.len() as f64;
+                    (group_by_day(values, timezone).len(), vec![Period { from, to: to + HOUR_MILLIS, value }])
+                }
+                _ => (0, Vec::new()),
+            };
+            return GuidelineReport {
+                pollutant: guideline.pollutant,
+                standard: guideline.standard,
+                averaging: guideline.averaging,
+                limit: guideline.limit,
+                days,
+                exceedance_days: None,
+                worst,
+            };
+        }
+    };
+
+    // worst average of each day, attributed to the day the period ends in
+    let mut daily_max: BTreeMap<NaiveDate, f64> = BTreeMap::new();
+    for period in &periods {
+        let max = daily_max.entry(local_date(period.to - 1, timezone)).or_insert(period.value);
+        *max = max.max(period.value);
+    }
+
+    GuidelineReport {
+        pollutant: guideline.pollutant,
+        standard: guideline.standard,
+        averaging: guideline.averaging,
+        limit: guideline.limit,
+        days: daily_max.len(),
+        exceedance_days: Some(daily_max.values().filter(|&&max| max > guideline.limit).count()),
+        worst: worst_periods(periods),
+    }
+}
+
+/// Rolling means over windows of `hours` hours ending at each hourly value.
+/// Windows with less than 75% data coverage are skipped.
+fn rolling_means(values: &[(i64, f64)], hours: u32) -> Vec<Period> {
+    let window = hours as i64 * HOUR_MILLIS;
+    let min_count = ((hours as f64 * 0.75).ceil() as usize).max(1);
+    let mut periods = Vec::new();
+    let mut start = 0;
+    let mut sum = 0.;
+    for (end, &(timestamp, value)) in values.iter().enumerate() {
+        sum += value;
+        while values[start].0 <= timestamp - window {
+            sum -= values[start].1;
+            start += 1;
+        }
+        let count = end - start + 1;
+        if count >= min_count {
+            periods.push(Period {
+                from: timestamp + HOUR_MILLIS - window,
+                to: timestamp + HOUR_MILLIS,
+                value: sum / count as f64,
+            });
+        }
+    }
+    periods
+}
+
+/// Calendar day means in the given time zone. Days with less than 18 hourly values are skipped.
+fn daily_means(values: &[(i64, f64)], timezone: Tz) -> Vec<Period> {
+    group_by_day(values, timezone).into_iter()
+        .filter(|(_, values)| values.len() >= 18)
+        .filter_map(|(date, values)| Some(Period {
+            from: day_start(date, timezone)?,
+            to: day_start(date.succ(), timezone)?,
+            value: values.iter().sum::<f64>() / values.len() as f64,
+        }))
+        .collect()
+}
+
+/// Returns the start of a local day, which isn't midnight if the clocks are put forward at midnight.
+fn day_start(date: NaiveDate, timezone: Tz) -> Option<i64> {
+    (0..3)
+        .find_map(|hour| timezone.from_local_datetime(&date.and_hms(hour, 0, 0)).earliest())
+        .map(|start| start.timestamp_millis())
+}
+
+fn group_by_day(values: &[(i64, f64)], timezone: Tz) -> BTreeMap<NaiveDate, Vec<f64>> {
+    let mut days: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for &(timestamp, value) in values {
+        days.entry(local_date(timestamp, timezone)).or_default().push(value);
+    }
+    days
+}
+
+fn local_date(timestamp: i64, timezone: Tz) -> NaiveDate {
+    timezone.timestamp_millis(timestamp).date().naive_local()
+}
+
+fn worst_periods(mut periods: Vec<Period>) -> Vec<Period> {
+    periods.sort_by(|a, b| b.value.total_cmp(&a.value));
+    let mut worst: Vec<Period> = Vec::new();
+    for period in periods {
+        if worst.len() == NUM_WORST {
+            break;
+        }
+        if worst.iter().all(|w| period.to <= w.from || w.to <= period.from) {
+            worst.push(period);
+        }
+    }
+    worst
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hourly(from: i64, values: &[f64]) -> Vec<(i64, f64)> {
+        values.iter().enumerate().map(|(i, &value)| (from + i as i64 * HOUR_MILLIS, value)).collect()
+    }
+
+    #[test]
+    fn rolling_means_need_three_quarters_of_the_window() {
+        let values = hourly(0, &[1., 2., 3., 4., 5., 6., 7., 8., 9., 10.]);
+        let means: Vec<_> = rolling_means(&values, 8).iter().map(|period| period.value).collect();
+        assert_eq!(means, [3.5, 4., 4.5, 5.5, 6.5]);
+
+        let periods = rolling_means(&values, 1);
+        assert_eq!(periods.len(), 10);
+        assert_eq!((periods[0].from, periods[0].to, periods[0].value), (0, HOUR_MILLIS, 1.));
+
+        // 5 of 8 hours within the last window
+        let mut gappy = values.clone();
+        gappy.retain(|&(timestamp, _)| !(4 * HOUR_MILLIS..7 * HOUR_MILLIS).contains(&timestamp));
+        let means: Vec<_> = rolling_means(&gappy, 8).iter().map(|period| period.value).collect();
+        assert!(means.is_empty());
+    }
+
+    #[test]
+    fn worst_periods_dont_overlap() {
+        let worst = worst_periods(rolling_means(&hourly(0, &[1., 5., 9., 5., 1., 1., 7., 2.]), 1));
+        let worst: Vec<_> = worst.iter().map(|period| period.value).collect();
+        assert_eq!(worst, [9., 7., 5., 5., 2.]);
+        // doesn't panic on NaN
+        let periods = vec![Period { from: 0, to: 1, value: f64::NAN }, Period { from: 1, to: 2, value: 1. }];
+        assert_eq!(worst_periods(periods).len(), 2);
+
+        let worst = worst_periods(rolling_means(&hourly(0, &[1., 5., 9., 5., 1., 1., 7.]), 2));
+        let worst: Vec<_> = worst.iter().map(|period| (period.from / HOUR_MILLIS, period.value)).collect();
+        assert_eq!(worst, [(1, 7.), (5, 4.), (3, 3.)]);
+    }
+
+    #[test]
+    fn daily_means_of_complete_local_days() {
+        let timezone = chrono_tz::Europe::Berlin;
+        let date = NaiveDate::from_ymd(2021, 1, 15);
+        let from = day_start(date, timezone).unwrap();
+        assert_eq!(from, chrono::Utc.ymd(2021, 1, 14).and_hms(23, 0, 0).timestamp_millis());
+        let values = hourly(from, &[[10.; 12], [20.; 12]].concat());
+        let periods = daily_means(&values, timezone);
+        assert_eq!(periods.len(), 1);
+        assert_eq!((periods[0].from, periods[0].to, periods[0].value), (from, day_start(date.succ(), timezone).unwrap(), 15.));
+        assert!(daily_means(&values[..17], timezone).is_empty());
+        // the same hours span two days in UTC
+        assert_eq!(daily_means(&values, chrono_tz::UTC).len(), 1);
+        assert_eq!(group_by_day(&values, chrono_tz::UTC).len(), 2);
+
+        // the day the clocks are put forward has 23 hours
+        let date = NaiveDate::from_ymd(2021, 3, 28);
+        assert_eq!(day_start(date.succ(), timezone).unwrap() - day_start(date, timezone).unwrap(), 23 * HOUR_MILLIS);
+    }
+}
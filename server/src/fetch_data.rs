@@ -1,42 +1,110 @@
+use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Instant;
+
 use airq::{AirQ, Data14, FilePath};
 use thiserror::Error;
 use futures::{future, TryFutureExt, stream::{self, StreamExt, TryStreamExt}};
 use chrono::{Duration, Utc};
 use crate::MeasurementStorage;
+use crate::metrics::FetchMetrics;
+use crate::storage::SyncState;
+
+/// number of files downloaded from the airQ in parallel
+const CONCURRENT_REQUESTS: usize = 3;
+/// number of failed downloads after which a file isn't retried anymore
+pub const MAX_ATTEMPTS: u32 = 5;
 
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("sqlx error: {0}")]
     Sqlx(#[from] sqlx::Error),
+    #[error("migration error: {0}")]
+    Migrate(#[from] sqlx::migrate::MigrateError),
     #[error("airq error: {0}")]
     Airq(#[from] airq::Error),
+    #[error("request error: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("influxdb error: {0}")]
+    InfluxDb(String),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("parquet error: {0}")]
+    Parquet(#[from] parquet::errors::ParquetError),
+    #[error("mqtt error: {0}")]
+    Mqtt(#[from] rumqttc::ClientError),
+    #[error("the airq address is not known yet")]
+    NotConnected,
+    #[error("the {0} aggregation isn't supported by this storage backend")]
+    UnsupportedAggregation(&'static str),
 }
 
 pub struct FetchData {
-    airq: AirQ,
+    password: String,
+    airq: RwLock<Option<AirQ>>,
+    /// number of consecutive failed requests
+    failures: AtomicU32,
+    pub metrics: FetchMetrics,
 }
 
 impl FetchData {
-    pub fn new(ip: &str, password: &str) -> FetchData {
-        let airq = AirQ::new(ip, password);
-        FetchData { airq }
+    pub fn new(password: &str) -> FetchData {
+        FetchData {
+            password: password.to_string(),
+            airq: RwLock::new(None),
+            failures: AtomicU32::new(0),
+            metrics: FetchMetrics::default(),
+        }
     }
 
-    pub async fn fetch_current(&self) -> Result<Data14, Error> {
-        Ok(self.airq.data_14().await?)
+    pub fn set_address(&self, addr: &str) {
+        *self.airq.write().unwrap() = Some(AirQ::new(addr, &self.password));
+        self.failures.store(0, Ordering::SeqCst);
     }
 
-    pub async fn fetch_data(&self, storage: &dyn MeasurementStorage) -> Result<(), Error> {
-        let last_timestamps = storage.last_timestamps().await?;
+    pub fn is_connected(&self) -> bool {
+        self.airq.read().unwrap().is_some()
+    }
 
-        const CONCURRENT_REQUESTS: usize = 3;
+    /// Number of consecutive requests to the airQ which failed.
+    pub fn failures(&self) -> u32 {
+        self.failures.load(Ordering::SeqCst)
+    }
+
+    fn airq(&self) -> Result<AirQ, Error> {
+        self.airq.read().unwrap().clone().ok_or(Error::NotConnected)
+    }
+
+    fn track<T>(&self, res: Result<T, airq::Error>) -> Result<T, Error> {
+        match &res {
+            Ok(_) => self.failures.store(0, Ordering::SeqCst),
+            Err(e) => {
+                self.failures.fetch_add(1, Ordering::SeqCst);
+                self.metrics.error(e);
+            },
+        }
+        Ok(res?)
+    }
+
+    pub async fn fetch_current(&self) -> Result<Data14, Error> {
+        let airq = self.airq()?;
+        let start = Instant::now();
+        let res = airq.data_14().await;
+        self.metrics.fetch_current_duration.observe(start.elapsed());
+        self.track(res)
+    }
+
+    pub async fn fetch_data(&self, device: &str, storage: &dyn MeasurementStorage) -> Result<(), Error> {
+        let airq = self.airq()?;
+        let start = Instant::now();
+        let last_timestamps = storage.last_timestamps(device).await?;
 
         // dirbuff contains the current and last month
         let dirbuff_start = (Utc::now() - Duration::days(25)).timestamp() as u64;
         let mut timestamps: Vec<_> = match last_timestamps {
             // use dirbuff as an optimization if possible
             Some((from_file, from_ts)) if dirbuff_start < from_ts => {
-                let files = self.airq.dirbuff().await?;
+                let files = self.track(airq.dirbuff().await)?;
                 files.into_iter().filter(|f| *f >= from_file).collect()
             },
             // fall back to full directory listing
@@ -46,30 +114,33 @@ impl FetchData {
                 let last_file_day = last_timestamps.map(|(p, _)| p.day);
                 let last_file_timestamps = last_timestamps.map(|(p, _)| p.timestamp);
 
-                let years = self.airq.dir("/").await?.into_iter()
+                let years = self.track(airq.dir("/").await)?.into_iter()
                     .filter_map(|year| year.parse().ok())
                     .filter(|&year| Some(year) >= last_file_year)
                     .collect::<Vec<_>>();
                 println!("{} years", years.len());
                 let months = stream::iter(years)
-                    .map(|year| self.airq.dir(format!("/{}", year)).map_ok(move |months| (year, months)))
+                    .map(|year| airq.dir(format!("/{}", year)).map_ok(move |months| (year, months)))
                     .buffer_unordered(CONCURRENT_REQUESTS)
+                    .inspect_err(|e| self.metrics.error(e))
                     .map_ok(|(year, months)| stream::iter(months).map(move |month| -> Result<_, Error> { Ok((year, month.parse::<u8>().unwrap())) }))
                     .try_flatten()
                     .try_filter(|&(year, month)| future::ready(Some(year) > last_file_year || Some(month) >= last_file_month))
                     .try_collect::<Vec<_>>().await?;
                 println!("{} months", months.len());
                 let days = stream::iter(months)
-                    .map(|(year, month)| self.airq.dir(format!("/{}/{}", year, month)).map_ok(move |days| (year, month, days)))
+                    .map(|(year, month)| airq.dir(format!("/{}/{}", year, month)).map_ok(move |days| (year, month, days)))
                     .buffer_unordered(CONCURRENT_REQUESTS)
+                    .inspect_err(|e| self.metrics.error(e))
                     .map_ok(|(year, month, days)| stream::iter(days).map(move |day| -> Result<_, Error> { Ok((year, month, day.parse().unwrap())) }))
                     .try_flatten()
                     .try_filter(|&(year, month, day)| future::ready(Some(year) > last_file_year || Some(month) > last_file_month || Some(day) >= last_file_day))
                     .try_collect::<Vec<_>>().await?;
                 println!("{} days", days.len());
                 let timestamps = stream::iter(days)
-                    .map(|(year, month, day)| self.airq.dir(format!("/{}/{}/{}", year, month, day)).map_ok(move |timestamps| (year, month, day, timestamps)))
+                    .map(|(year, month, day)| airq.dir(format!("/{}/{}/{}", year, month, day)).map_ok(move |timestamps| (year, month, day, timestamps)))
                     .buffer_unordered(CONCURRENT_REQUESTS)
+                    .inspect_err(|e| self.metrics.error(e))
                     .map_ok(|(year, month, day, timestamps)| stream::iter(timestamps).map(move |timestamp| -> Result<_, Error> { Ok((year, month, day, timestamp.parse().unwrap())) }))
                     .try_flatten()
                     .try_filter(|&(year, month, day, timestamp)| future::ready(Some(year) > last_file_year || Some(month) > last_file_month || Some(day) > last_file_day || Some(timestamp) >= last_file_timestamps))
@@ -80,20 +151,76 @@ impl FetchData {
         };
 
         timestamps.sort_unstable();
+        // the storage may only know the day of the last file, skip files before the one containing the last measurement
+        if let Some((_, last_ts)) = last_timestamps {
+            if let Some(last_file) = timestamps.iter().rposition(|file| file.timestamp * 1000 <= last_ts) {
+                timestamps.drain(..last_file);
+            }
+        }
         println!("{} timestamp(s)", timestamps.len());
 
-        let mut entries = stream::iter(timestamps)
-            .inspect(|file| println!("fetching {}", file.path()))
-            .map(|file| self.airq.file_data_14(file.path()).map_ok(move |data| (file, data)))
-            .buffer_unordered(CONCURRENT_REQUESTS)
-            .inspect_err(|e| eprintln!("Error fetching data from airQ: {:?}", e))
-            .filter_map(|res| future::ready(res.ok()))
-            ;
+        // files which failed in earlier runs, they are older than the last stored measurement
+        let retries: Vec<_> = storage.unsynced_files(device).await?.into_iter()
+            .filter(|sync| sync.attempts < MAX_ATTEMPTS && timestamps.binary_search(&sync.file).is_err())
+            .map(|sync| sync.file)
+            .collect();
+        if !retries.is_empty() {
+            println!("retrying {} file(s)", retries.len());
+        }
 
-        storage.store_entries(&mut entries, last_timestamps.map(|(_, ts)| ts)).await?;
+        storage.set_file_state(device, &timestamps, SyncState::Pending, None).await?;
+        let mut rows = self.download(&airq, device, retries, None, storage).await?;
+        rows += self.download(&airq, device, timestamps, last_timestamps.map(|(_, ts)| ts), storage).await?;
+        self.metrics.rows_inserted.fetch_add(rows, Ordering::Relaxed);
+        self.metrics.fetch_data_duration.observe(start.elapsed());
+        self.metrics.last_fetch_data_success.store(Utc::now().timestamp() as u64, Ordering::Relaxed);
         Ok(())
     }
 
+    /// Downloads and stores the given files and records which of them succeeded or failed.
+    async fn download(&self, airq: &AirQ, device: &str, files: Vec<FilePath>, last_timestamp: Option<u64>, storage: &dyn MeasurementStorage) -> Result<u64, Error> {
+        if files.is_empty() {
+            return Ok(0);
+        }
+        let downloaded = Mutex::new(Vec::new());
+        let failed = Mutex::new(Vec::new());
+        let stored = {
+            let mut entries = stream::iter(files)
+                .inspect(|file| println!("fetching {}", file.path()))
+                .map(|file| airq.file_data_14(file.path()).map_ok(move |data| (file, data)).map_err(move |e| (file, e)))
+                .buffer_unordered(CONCURRENT_REQUESTS)
+                .filter_map(|res| future::ready(match res {
+                    Ok((file, data)) => {
+                        downloaded.lock().unwrap().push(file);
+                        Some((file, data))
+                    },
+                    Err((file, e)) => {
+                        eprintln!("Error fetching {} from airQ: {:?}", file.path(), e);
+                        self.metrics.error(&e);
+                        failed.lock().unwrap().push((file, e.to_string()));
+                        None
+                    },
+                }));
+            storage.store_entries(device, &mut entries, last_timestamp).await
+        };
+
+        for (file, error) in failed.into_inner().unwrap() {
+            storage.set_file_state(device, &[file], SyncState::Failed, Some(&error)).await?;
+        }
+        let downloaded = downloaded.into_inner().unwrap();
+        match stored {
+            Ok(rows) => {
+                storage.set_file_state(device, &downloaded, SyncState::Succeeded, None).await?;
+                Ok(rows)
+            },
+            // the downloaded files count as failed attempts, so that they are retried a limited number of times
+            Err(e) => {
+                storage.set_file_state(device, &downloaded, SyncState::Failed, Some(&e.to_string())).await?;
+                Err(e)
+            },
+        }
+    }
+
     // pub async fn fetch_data(&self, pg_pool: &PgPool) -> Result<(), Error> {
     // }
 }
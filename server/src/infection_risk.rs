@@ -0,0 +1,60 @@
+use serde::Serialize;
+
+use crate::room::Room;
+
+/// CO₂ concentration of exhaled breath in ppm (Rudnick & Milton 2003)
+const EXHALED_CO2: f64 = 38000.;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct InfectionRisk {
+    /// fraction of the inhaled air which has already been exhaled by someone else in the room
+    pub rebreathed_fraction: f64,
+    /// probability of a susceptible person getting infected within one hour
+    /// if one infectious person is in the room
+    pub risk: f64,
+}
+
+/// Computes the rebreathed air fraction from the CO₂ concentration
+/// and the resulting Wells–Riley infection risk following Rudnick & Milton 2003:
+/// `P = 1 - exp(-f·I·q·t / n)` with a single infector `I` and an exposure time `t` of one hour.
+pub fn infection_risk(room: &Room, co2: f64) -> InfectionRisk {
+    let rebreathed_fraction = ((co2 - room.outdoor_co2) / EXHALED_CO2).max(0.);
+    let quanta = rebreathed_fraction * room.activity.quanta_emission_rate() / room.occupants.max(1.);
+    InfectionRisk {
+        rebreathed_fraction,
+        risk: 1. - (-quanta).exp(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::room::Activity;
+    use super::*;
+
+    #[test]
+    fn outdoor_air_is_not_rebreathed() {
+        let room = Room::default();
+        for co2 in [room.outdoor_co2, room.outdoor_co2 - 20.] {
+            let risk = infection_risk(&room, co2);
+            assert_eq!((risk.rebreathed_fraction, risk.risk), (0., 0.));
+        }
+    }
+
+    #[test]
+    fn empty_room_counts_as_the_infector_alone() {
+        let room = Room { occupants: 0., activity: Activity::Resting, outdoor_co2: 400., ..Room::default() };
+        let risk = infection_risk(&room, 4200.);
+        assert!((risk.rebreathed_fraction - 0.1).abs() < 1e-12);
+        assert!((risk.risk - (1. - (-0.1f64).exp())).abs() < 1e-12);
+    }
+
+    #[test]
+    fn rudnick_milton_classroom() {
+        // 1000 ppm indoors at 380 ppm outdoors is a rebreathed fraction of about 1.6 % (Rudnick & Milton 2003)
+        let room = Room { occupants: 30., activity: Activity::Talking, outdoor_co2: 380., ..Room::default() };
+        let risk = infection_risk(&room, 1000.);
+        assert!((risk.rebreathed_fraction - 0.0163).abs() < 1e-4);
+        // P = 1 - exp(-f·I·q·t / n) = 1 - exp(-0.0163 · 1 · 10 · 1 / 30)
+        assert!((risk.risk - 0.00542).abs() < 1e-5);
+    }
+}
@@ -5,8 +5,15 @@ use std::panic::AssertUnwindSafe;
 use std::sync::{Arc, Mutex};
 
 use serde::{Serialize, Deserialize};
+use chrono::TimeZone;
 use rocket::State;
+use rocket::http::ContentType;
+use rocket::response::Stream;
+use rocket::response::content::Content;
+use rocket::response::status::BadRequest;
 use rocket_contrib::{json::Json, serve::StaticFiles};
+use tokio::io::{self, AsyncWriteExt, DuplexStream};
+use tokio::sync::mpsc;
 use tokio::time;
 use futures::FutureExt;
 use airq::{Data14, AirQ};
@@ -14,14 +21,36 @@ use lazy_static::lazy_static;
 
 type Result<T> = std::result::Result<T, rocket::response::Debug<Error>>;
 
+mod acoustics;
+mod benchmark;
+mod config;
+mod device;
+mod downsample;
+mod export;
+mod exposure;
 mod fetch_data;
 mod include_static_files;
+mod infection_risk;
+mod metrics;
+mod mqtt;
+mod occupancy;
+mod particles;
+mod room;
 mod storage;
+mod weather;
 
-use fetch_data::FetchData;
 use include_static_files::IncludedStaticFiles;
 use crate::fetch_data::Error;
-use crate::storage::{MeasurementStorage, Postgres, Sevendays};
+use crate::exposure::GuidelineReport;
+use crate::acoustics::AcousticReport;
+use crate::config::{Config, ExportConfig, Retention, StorageConfig};
+use crate::device::{Device, DeviceInfo, Devices};
+use crate::downsample::Downsampling;
+use crate::export::{Columns, ExportFile, Format};
+use crate::infection_risk::InfectionRisk;
+use crate::mqtt::Mqtt;
+use crate::storage::{Aggregation, FileSync, InfluxDb, MeasurementStorage, Postgres, RetentionReport, Sevendays, Sqlite, SyncState, is_selected};
+use crate::weather::Tendency;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Measurement {
@@ -44,6 +73,22 @@ pub struct Measurement {
     oxygen: Option<f64>,
     o3: Option<f64>,
     so2: Option<f64>,
+    uptime: Option<i64>,
+    measuretime: Option<f64>,
+    bat: Option<f64>,
+    door_event: Option<f64>,
+    window_open: Option<f64>,
+    humidity_abs_delta: Option<f64>,
+    co2_delta: Option<f64>,
+    cnt0_3: Option<f64>,
+    cnt0_5: Option<f64>,
+    cnt1: Option<f64>,
+    cnt2_5: Option<f64>,
+    cnt5: Option<f64>,
+    cnt10: Option<f64>,
+    typ_ps: Option<f64>,
+    /// averages of the numeric fields the server doesn't know by name
+    rest: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Serialize)]
@@ -52,90 +97,552 @@ pub struct Timestamps {
     last: Option<i64>,
 }
 
-#[rocket::get("/timestamps")]
-async fn timestamps(storage: State<'_, Arc<dyn MeasurementStorage>>) -> Result<Json<Timestamps>> {
-    Ok(Json(storage.timestamps().await?))
+#[rocket::get("/devices")]
+async fn devices(devices: State<'_, Devices>) -> Json<Vec<DeviceInfo>> {
+    Json(devices.iter().map(|device| device.info()).collect())
+}
+
+/// Resolves the device name or id given in a route to the device and its id in the storage.
+fn resolve(devices: &Devices, device: &str) -> Option<(Arc<Device>, String)> {
+    let device = devices.get(device)?;
+    let id = device.id()?;
+    Some((Arc::clone(device), id))
+}
+
+#[rocket::get("/timestamps/<device>")]
+async fn timestamps(storage: State<'_, Arc<dyn MeasurementStorage>>, devices: State<'_, Devices>, device: String) -> Result<Option<Json<Timestamps>>> {
+    let (_, id) = match resolve(&devices, &device) {
+        Some(device) => device,
+        None => return Ok(None),
+    };
+    Ok(Some(Json(storage.timestamps(&id).await?)))
 }
 
 const MAX_DATAPOINTS: u64 = 500;
+/// largest point budget `/data` accepts
+const MAX_POINTS_LIMIT: u64 = 10_000;
+/// number of bucket sizes between two consecutive buckets of `/data` above which there is a gap
+const MAX_GAP: i64 = 2;
+/// the pressure tendency is computed over three hours, plus some slack for missing measurements
+const TENDENCY_LOOKBACK: u64 = (3 * 60 + 10) * 60 * 1000;
 
 lazy_static! {
-    static ref CURRENT_DATA: Mutex<Option<Data14>> = Mutex::new(None);
+    /// latest readout of each device by device id
+    static ref CURRENT_DATA: Mutex<HashMap<String, Data14>> = Mutex::new(HashMap::new());
+}
+
+
+#[derive(Debug, Serialize)]
+pub struct CurrentData {
+    #[serde(flatten)]
+    data: Data14,
+    infection_risk: InfectionRisk,
+    pressure_tendency: Option<Tendency>,
 }
 
+#[rocket::get("/data/<device>/current")]
+async fn data_current(storage: State<'_, Arc<dyn MeasurementStorage>>, devices: State<'_, Devices>, device: String) -> Result<Option<Json<Option<CurrentData>>>> {
+    let (device, id) = match resolve(&devices, &device) {
+        Some(device) => device,
+        None => return Ok(None),
+    };
+    let data = match CURRENT_DATA.lock().unwrap().get(&id).cloned() {
+        Some(data) => data,
+        None => return Ok(Some(Json(None))),
+    };
+    let now = data.data11.timestamp;
+    // the current data doesn't depend on the storage, the tendency is left out if it fails
+    let pressure_tendency = match storage.data(&id, now.saturating_sub(TENDENCY_LOOKBACK), now, 1, 2 * 60 * 1000).await {
+        Ok(last_hours) => weather::tendency(&last_hours, now as i64, 30 * 60 * 1000, device.room.altitude),
+        Err(e) => {
+            eprintln!("Error reading the pressure tendency of {}: {:?}", device.name, e);
+            None
+        },
+    };
+    Ok(Some(Json(Some(CurrentData {
+        infection_risk: infection_risk::infection_risk(&device.room, data.data11.co2[0]),
+        pressure_tendency,
+        data,
+    }))))
+}
 
-#[rocket::get("/data/current")]
-async fn data_current() -> Json<Option<Data14>> {
-    Json(CURRENT_DATA.lock().unwrap().clone())
+/// Rejects a time range ending before it starts.
+fn check_range(first: u64, last: u64) -> std::result::Result<(), BadRequest<String>> {
+    if first > last {
+        return Err(BadRequest(Some(format!("first ({}) is after last ({})", first, last))));
+    }
+    Ok(())
 }
 
-#[rocket::get("/data/<first>/<last>")]
-async fn data(storage: State<'_, Arc<dyn MeasurementStorage>>, first: u64, last: u64) -> Result<Json<HashMap<&'static str, Vec<f64>>>> {
-    let num_measurements = (last - first) / (2 * 60 * 1000);
-    let combine_datapoints = (num_measurements + MAX_DATAPOINTS) / MAX_DATAPOINTS;
+/// Returns how many datapoints need to be combined and how many milliseconds that corresponds to
+/// to return at most `max_datapoints` for the given time range.
+fn combine(first: u64, last: u64, max_datapoints: u64) -> (u64, u64) {
+    let num_measurements = last.saturating_sub(first) / (2 * 60 * 1000);
+    let combine_datapoints = (num_measurements + max_datapoints) / max_datapoints;
     let combine_millis = combine_datapoints * 2 * 60 * 1000;
-    println!("getting data for {}, {}, {}", num_measurements, combine_datapoints, combine_millis);
+    (combine_datapoints, combine_millis)
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum DataResponse {
+    /// fields as columns sharing the column `timestamp`
+    Table(HashMap<String, Vec<f64>>),
+    /// `[timestamp, value]` points of each field
+    Points(HashMap<String, Vec<(f64, f64)>>),
+}
+
+/// Returns all fields of the measurements in the given range, `null` where missing.
+/// Fields unknown to the server are included by the name the airQ sends them with.
+///
+/// The range is reduced to `points` (default `MAX_DATAPOINTS`) per field by `downsample`:
+/// by default the buckets are averaged unless `aggregate` lists other aggregations, e.g. `?aggregate=min,avg,max`.
+/// Fields of aggregations other than `avg` are suffixed with the aggregation, e.g. `co2.max`.
+/// `lttb` and `m4` select points of each field separately and return them as `[timestamp, value]`.
+/// `fields` limits the result to a comma separated list of fields, e.g. `?fields=co2,temperature`.
+///
+/// Where consecutive buckets are further apart than `MAX_GAP` buckets, a row with only a timestamp marks the gap.
+#[rocket::get("/data/<device>/<first>/<last>?<aggregate>&<downsample>&<points>&<fields>")]
+#[allow(clippy::too_many_arguments)]
+async fn data(
+    storage: State<'_, Arc<dyn MeasurementStorage>>, devices: State<'_, Devices>, device: String, first: u64, last: u64,
+    aggregate: Option<String>, downsample: Option<String>, points: Option<u64>, fields: Option<String>,
+) -> Result<Option<std::result::Result<Json<DataResponse>, BadRequest<String>>>> {
+    let (_, id) = match resolve(&devices, &device) {
+        Some(device) => device,
+        None => return Ok(None),
+    };
+    if let Err(error) = check_range(first, last) {
+        return Ok(Some(Err(error)));
+    }
+    let fields = parse_fields(fields);
+    let fields = fields.as_deref();
+    let aggregations = match aggregate.as_deref().map(Aggregation::parse_list) {
+        None => vec![Aggregation::Avg],
+        Some(Ok(aggregations)) => aggregations,
+        Some(Err(error)) => return Ok(Some(Err(BadRequest(Some(error))))),
+    };
+    let downsampling = match downsample.as_deref().map(Downsampling::parse) {
+        None => Downsampling::Avg,
+        Some(Ok(downsampling)) => downsampling,
+        Some(Err(error)) => return Ok(Some(Err(BadRequest(Some(error))))),
+    };
+    if downsampling != Downsampling::Avg && aggregate.is_some() {
+        return Ok(Some(Err(BadRequest(Some("aggregate only applies to downsample=avg".to_string())))));
+    }
+    let points = points.unwrap_or(MAX_DATAPOINTS);
+    if !(1..=MAX_POINTS_LIMIT).contains(&points) {
+        return Ok(Some(Err(BadRequest(Some(format!("points must be between 1 and {}", MAX_POINTS_LIMIT))))));
+    }
+
+    if downsampling != Downsampling::Avg {
+        // every measurement, the gap markers split the fields into segments downsampled separately
+        let measurements = storage.aggregate(&id, first, last, 1, 2 * 60 * 1000, &[Aggregation::Avg], fields).await?
+            .pop().unwrap_or_default();
+        let mut table = data_table(&measurements, 2 * 60 * 1000);
+        let timestamps = table.remove("timestamp").unwrap_or_default();
+        table.retain(|key, _| is_selected(fields, key));
+        let select = match downsampling {
+            Downsampling::Lttb => downsample::lttb,
+            _ => downsample::m4,
+        };
+        let series = table.into_iter()
+            .map(|(key, values)| {
+                let field: Vec<_> = timestamps.iter().copied().zip(values).collect();
+                (key, downsample::segmented(&field, points as usize, select))
+            })
+            .collect();
+        return Ok(Some(Ok(Json(DataResponse::Points(series)))));
+    }
+
+    let (combine_datapoints, combine_millis) = combine(first, last, points);
+
+    let series = match storage.aggregate(&id, first, last, combine_datapoints, combine_millis, &aggregations, fields).await {
+        Ok(series) => series,
+        Err(error @ Error::UnsupportedAggregation(_)) => return Ok(Some(Err(BadRequest(Some(error.to_string()))))),
+        Err(error) => return Err(error.into()),
+    };
+
+    // the series have the same buckets, so the timestamps of the first one apply to all
+    let mut map = HashMap::new();
+    for (i, (aggregation, measurements)) in aggregations.iter().zip(&series).enumerate() {
+        for (key, values) in data_table(measurements, combine_millis) {
+            let key = match aggregation {
+                _ if key == "timestamp" && i > 0 => continue,
+                _ if key != "timestamp" && !is_selected(fields, &key) => continue,
+                Aggregation::Avg => key,
+                _ if key == "timestamp" => key,
+                aggregation => format!("{}.{}", key, aggregation.as_str()),
+            };
+            map.insert(key, values);
+        }
+    }
+    Ok(Some(Ok(Json(DataResponse::Table(map)))))
+}
+
+/// Splits the comma separated list of the `fields` parameter, all fields are selected if not given.
+fn parse_fields(fields: Option<String>) -> Option<Vec<String>> {
+    fields.map(|fields| fields.split(',').map(|field| field.trim().to_string()).collect())
+}
+
+/// Streams every stored measurement in the range as one JSON object per line (NDJSON) without combining any.
+/// Fields unknown to the server are included by the name the airQ sends them with,
+/// `fields` limits the fields like for `/data/<device>/<first>/<last>`.
+///
+/// Errors after the response started can't be reported anymore and end the stream early.
+#[rocket::get("/data/<device>/<first>/<last>/raw?<fields>")]
+async fn data_raw(storage: State<'_, Arc<dyn MeasurementStorage>>, devices: State<'_, Devices>, device: String, first: u64, last: u64, fields: Option<String>) -> Option<Content<Stream<DuplexStream>>> {
+    let (_, id) = resolve(&devices, &device)?;
+    let fields = parse_fields(fields);
+    let storage = storage.inner().clone();
+    let (mut writer, reader) = io::duplex(64 * 1024);
+    tokio::spawn(async move {
+        let (sender, mut receiver) = mpsc::channel(1024);
+        let line_fields = fields.clone();
+        // the receiver is dropped when the client disconnects, which stops the query
+        let write = async move {
+            while let Some(measurement) = receiver.recv().await {
+                if writer.write_all(&ndjson_line(&measurement, line_fields.as_deref())).await.is_err() {
+                    break;
+                }
+            }
+        };
+        let query = storage.raw_rows(&id, first, last, fields.as_deref(), sender);
+        let (result, ()) = futures::join!(query, write);
+        if let Err(e) = result {
+            eprintln!("Error streaming raw data of {}: {:?}", id, e);
+        }
+    });
+    Some(Content(ContentType::new("application", "x-ndjson"), Stream::from(reader)))
+}
+
+/// Serializes the measurement as a line of NDJSON with the fields of `rest` at the top level.
+fn ndjson_line(measurement: &Measurement, fields: Option<&[String]>) -> Vec<u8> {
+    let object: serde_json::Map<_, _> = export::flat_fields(measurement).into_iter()
+        .filter(|(key, _)| key == "timestamp" || is_selected(fields, key))
+        .collect();
+    let mut line = serde_json::to_vec(&object).unwrap();
+    line.push(b'\n');
+    line
+}
+
+/// Exports the measurements of a device in the range `from` to `to` as a CSV or Parquet file,
+/// with the ISO 8601 time in the time zone `tz` and one column per field.
+///
+/// `resolution` is `raw` (default) or the bucket size of averages like `15m` or `1h`,
+/// `uncertainty=true` adds a column with the uncertainty of each field, which is only stored
+/// for the fields unknown to the server.
+#[rocket::get("/export?<device>&<from>&<to>&<fields>&<resolution>&<format>&<tz>&<uncertainty>")]
+#[allow(clippy::too_many_arguments)]
+async fn export_file(
+    storage: State<'_, Arc<dyn MeasurementStorage>>, devices: State<'_, Devices>, config: State<'_, ExportConfig>,
+    device: String, from: String, to: String, fields: Option<String>, resolution: Option<String>, format: Option<String>,
+    tz: Option<String>, uncertainty: Option<bool>,
+) -> Result<Option<std::result::Result<ExportFile, BadRequest<String>>>> {
+    let (_, id) = match resolve(&devices, &device) {
+        Some(device) => device,
+        None => return Ok(None),
+    };
+    let parsed = (|| -> std::result::Result<_, String> {
+        let timezone = match &tz {
+            Some(tz) => tz.parse()?,
+            None => config.timezone(),
+        };
+        let first = export::parse_time(&from, timezone)?;
+        let last = export::parse_time(&to, timezone)?;
+        let resolution = resolution.as_deref().map_or(Ok(None), export::parse_resolution)?;
+        let format = format.as_deref().map_or(Ok(Format::Csv), Format::parse)?;
+        let fields = parse_fields(fields).unwrap_or_else(|| export::DEFAULT_FIELDS.iter().map(|field| field.to_string()).collect());
+        let columns = Columns::new(fields, uncertainty.unwrap_or(false), timezone)?;
+        Ok((timezone, first, last, resolution, format, columns))
+    })();
+    let (timezone, first, last, resolution, format, columns) = match parsed {
+        Ok(parsed) => parsed,
+        Err(error) => return Ok(Some(Err(BadRequest(Some(error))))),
+    };
+    let day = |millis: u64| timezone.timestamp_millis(millis as i64).format("%Y-%m-%d");
+    let name: String = device.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect();
+    let filename = format!("airq-{}-{}-{}", name, day(first), day(last));
+    Ok(Some(Ok(export::export(storage.inner().clone(), id, first, last, resolution, columns, format, &filename).await?)))
+}
+
+/// Returns the fields of the measurements as columns, with gap markers between buckets too far apart.
+fn data_table(measurements: &[Measurement], combine_millis: u64) -> HashMap<String, Vec<f64>> {
+    // NaN is serialized as null
+    let mut map: HashMap<_, Vec<_>> = HashMap::new();
+    let mut rows = 0;
+    let mut previous = None;
+    for entry in measurements {
+        if let (Some(previous), Some(timestamp)) = (previous, entry.timestamp) {
+            if timestamp - previous > MAX_GAP * combine_millis as i64 {
+                map.entry("timestamp".to_string()).or_default().push((previous + combine_millis as i64) as f64);
+                rows += 1;
+                for values in map.values_mut() {
+                    values.resize(rows, f64::NAN);
+                }
+            }
+        }
+        previous = entry.timestamp;
+
+        map.entry("timestamp".to_string()).or_default().push(entry.timestamp.map_or(f64::NAN, |timestamp| timestamp as f64));
+        map.entry("health".to_string()).or_default().push(entry.health.unwrap_or(f64::NAN));
+        map.entry("performance".to_string()).or_default().push(entry.performance.unwrap_or(f64::NAN));
+        map.entry("tvoc".to_string()).or_default().push(entry.tvoc.unwrap_or(f64::NAN));
+        map.entry("humidity".to_string()).or_default().push(entry.humidity.unwrap_or(f64::NAN));
+        map.entry("humidity_abs".to_string()).or_default().push(entry.humidity_abs.unwrap_or(f64::NAN));
+        map.entry("temperature".to_string()).or_default().push(entry.temperature.unwrap_or(f64::NAN));
+        map.entry("dewpt".to_string()).or_default().push(entry.dewpt.unwrap_or(f64::NAN));
+        map.entry("sound".to_string()).or_default().push(entry.sound.unwrap_or(f64::NAN));
+        map.entry("pressure".to_string()).or_default().push(entry.pressure.unwrap_or(f64::NAN));
+        map.entry("no2".to_string()).or_default().push(entry.no2.unwrap_or(f64::NAN));
+        map.entry("co".to_string()).or_default().push(entry.co.unwrap_or(f64::NAN));
+        map.entry("co2".to_string()).or_default().push(entry.co2.unwrap_or(f64::NAN));
+        map.entry("pm1".to_string()).or_default().push(entry.pm1.unwrap_or(f64::NAN));
+        map.entry("pm2_5".to_string()).or_default().push(entry.pm2_5.unwrap_or(f64::NAN));
+        map.entry("pm10".to_string()).or_default().push(entry.pm10.unwrap_or(f64::NAN));
+        map.entry("oxygen".to_string()).or_default().push(entry.oxygen.unwrap_or(f64::NAN));
+        map.entry("o3".to_string()).or_default().push(entry.o3.unwrap_or(f64::NAN));
+        map.entry("so2".to_string()).or_default().push(entry.so2.unwrap_or(f64::NAN));
+        map.entry("uptime".to_string()).or_default().push(entry.uptime.map_or(f64::NAN, |uptime| uptime as f64));
+        map.entry("measuretime".to_string()).or_default().push(entry.measuretime.unwrap_or(f64::NAN));
+        map.entry("bat".to_string()).or_default().push(entry.bat.unwrap_or(f64::NAN));
+        map.entry("door_event".to_string()).or_default().push(entry.door_event.unwrap_or(f64::NAN));
+        map.entry("window_open".to_string()).or_default().push(entry.window_open.unwrap_or(f64::NAN));
+        map.entry("humidity_abs_delta".to_string()).or_default().push(entry.humidity_abs_delta.unwrap_or(f64::NAN));
+        map.entry("co2_delta".to_string()).or_default().push(entry.co2_delta.unwrap_or(f64::NAN));
+        map.entry("cnt0_3".to_string()).or_default().push(entry.cnt0_3.unwrap_or(f64::NAN));
+        map.entry("cnt0_5".to_string()).or_default().push(entry.cnt0_5.unwrap_or(f64::NAN));
+        map.entry("cnt1".to_string()).or_default().push(entry.cnt1.unwrap_or(f64::NAN));
+        map.entry("cnt2_5".to_string()).or_default().push(entry.cnt2_5.unwrap_or(f64::NAN));
+        map.entry("cnt5".to_string()).or_default().push(entry.cnt5.unwrap_or(f64::NAN));
+        map.entry("cnt10".to_string()).or_default().push(entry.cnt10.unwrap_or(f64::NAN));
+        map.entry("typ_ps".to_string()).or_default().push(entry.typ_ps.unwrap_or(f64::NAN));
+        let rest = entry.rest.as_ref().and_then(|rest| rest.as_object());
+        for (key, value) in rest.into_iter().flatten() {
+            // also null for entries before the field first appeared
+            let values = map.entry(key.clone()).or_insert_with(|| vec![f64::NAN; rows]);
+            values.push(value.as_f64().unwrap_or(f64::NAN));
+        }
+        rows += 1;
+        for values in map.values_mut() {
+            values.resize(rows, f64::NAN);
+        }
+    }
+    map
+}
 
-    let measurements = storage.data(first, last, combine_datapoints, combine_millis).await?;
+#[rocket::get("/occupancy/<device>/<first>/<last>")]
+async fn data_occupancy(storage: State<'_, Arc<dyn MeasurementStorage>>, devices: State<'_, Devices>, device: String, first: u64, last: u64) -> Result<Option<std::result::Result<Json<HashMap<&'static str, Vec<f64>>>, BadRequest<String>>>> {
+    let (device, id) = match resolve(&devices, &device) {
+        Some(device) => device,
+        None => return Ok(None),
+    };
+    if let Err(error) = check_range(first, last) {
+        return Ok(Some(Err(error)));
+    }
+    let (combine_datapoints, combine_millis) = combine(first, last, MAX_DATAPOINTS);
+    let measurements = storage.data(&id, first, last, combine_datapoints, combine_millis).await?;
+
+    let mut map: HashMap<_, Vec<_>> = HashMap::new();
+    for entry in occupancy::estimate(&device.room, &measurements) {
+        map.entry("timestamp").or_default().push(entry.timestamp as f64);
+        map.entry("occupied").or_default().push(if entry.occupied { 1. } else { 0. });
+        map.entry("people").or_default().push(entry.people);
+    }
+    Ok(Some(Ok(Json(map))))
+}
+
+#[rocket::get("/infection-risk/<device>/<first>/<last>")]
+async fn data_infection_risk(storage: State<'_, Arc<dyn MeasurementStorage>>, devices: State<'_, Devices>, device: String, first: u64, last: u64) -> Result<Option<std::result::Result<Json<HashMap<&'static str, Vec<f64>>>, BadRequest<String>>>> {
+    let (device, id) = match resolve(&devices, &device) {
+        Some(device) => device,
+        None => return Ok(None),
+    };
+    if let Err(error) = check_range(first, last) {
+        return Ok(Some(Err(error)));
+    }
+    let (combine_datapoints, combine_millis) = combine(first, last, MAX_DATAPOINTS);
+    let measurements = storage.data(&id, first, last, combine_datapoints, combine_millis).await?;
 
     let mut map: HashMap<_, Vec<_>> = HashMap::new();
     for entry in measurements {
-        map.entry("timestamp").or_default().push(entry.timestamp.unwrap_or_default() as f64);
-        map.entry("health").or_default().push(entry.health.unwrap_or_default());
-        map.entry("performance").or_default().push(entry.performance.unwrap_or_default());
-        map.entry("tvoc").or_default().push(entry.tvoc.unwrap_or_default());
-        map.entry("humidity").or_default().push(entry.humidity.unwrap_or_default());
-        map.entry("humidity_abs").or_default().push(entry.humidity_abs.unwrap_or_default());
-        map.entry("temperature").or_default().push(entry.temperature.unwrap_or_default());
-        map.entry("dewpt").or_default().push(entry.dewpt.unwrap_or_default());
-        map.entry("sound").or_default().push(entry.sound.unwrap_or_default());
-        map.entry("pressure").or_default().push(entry.pressure.unwrap_or_default());
-        map.entry("no2").or_default().push(entry.no2.unwrap_or_default());
-        map.entry("co").or_default().push(entry.co.unwrap_or_default());
-        map.entry("co2").or_default().push(entry.co2.unwrap_or_default());
-        map.entry("pm1").or_default().push(entry.pm1.unwrap_or_default());
-        map.entry("pm2_5").or_default().push(entry.pm2_5.unwrap_or_default());
-        map.entry("pm10").or_default().push(entry.pm10.unwrap_or_default());
-        map.entry("oxygen").or_default().push(entry.oxygen.unwrap_or_default());
-        map.entry("o3").or_default().push(entry.o3.unwrap_or_default());
-        map.entry("so2").or_default().push(entry.so2.unwrap_or_default());
-    }
-    Ok(Json(map))
-}
-
-async fn fetch_current_data_regularly(airq_ip: String, password: String, storage: Arc<dyn MeasurementStorage>) {
-    let fetchdata = FetchData::new(&airq_ip, &password);
+        let (timestamp, co2) = match (entry.timestamp, entry.co2) {
+            (Some(timestamp), Some(co2)) => (timestamp, co2),
+            _ => continue,
+        };
+        let risk = infection_risk::infection_risk(&device.room, co2);
+        map.entry("timestamp").or_default().push(timestamp as f64);
+        map.entry("rebreathed_fraction").or_default().push(risk.rebreathed_fraction);
+        map.entry("risk").or_default().push(risk.risk);
+    }
+    Ok(Some(Ok(Json(map))))
+}
+
+#[rocket::get("/pressure-tendency/<device>/<first>/<last>")]
+async fn data_pressure_tendency(storage: State<'_, Arc<dyn MeasurementStorage>>, devices: State<'_, Devices>, device: String, first: u64, last: u64) -> Result<Option<std::result::Result<Json<HashMap<&'static str, Vec<f64>>>, BadRequest<String>>>> {
+    let (device, id) = match resolve(&devices, &device) {
+        Some(device) => device,
+        None => return Ok(None),
+    };
+    if let Err(error) = check_range(first, last) {
+        return Ok(Some(Err(error)));
+    }
+    let (combine_datapoints, combine_millis) = combine(first, last, MAX_DATAPOINTS);
+    let measurements = storage.data(&id, first.saturating_sub(TENDENCY_LOOKBACK), last, combine_datapoints, combine_millis).await?;
+    let max_gap = (2 * combine_millis).max(30 * 60 * 1000) as i64;
+
+    let mut map: HashMap<_, Vec<_>> = HashMap::new();
+    for timestamp in measurements.iter().filter_map(|entry| entry.timestamp).filter(|&ts| ts >= first as i64) {
+        let tendency = match weather::tendency(&measurements, timestamp, max_gap, device.room.altitude) {
+            Some(tendency) => tendency,
+            None => continue,
+        };
+        map.entry("timestamp").or_default().push(timestamp as f64);
+        map.entry("change").or_default().push(tendency.change);
+        map.entry("code").or_default().push(tendency.code as f64);
+        map.entry("zambretti").or_default().push(tendency.zambretti as f64);
+    }
+    Ok(Some(Ok(Json(map))))
+}
+
+#[rocket::get("/particles/<device>/<first>/<last>")]
+async fn particle_distribution(storage: State<'_, Arc<dyn MeasurementStorage>>, devices: State<'_, Devices>, device: String, first: u64, last: u64) -> Result<Option<std::result::Result<Json<HashMap<&'static str, Vec<f64>>>, BadRequest<String>>>> {
+    let (_, id) = match resolve(&devices, &device) {
+        Some(device) => device,
+        None => return Ok(None),
+    };
+    if let Err(error) = check_range(first, last) {
+        return Ok(Some(Err(error)));
+    }
+    let (combine_datapoints, combine_millis) = combine(first, last, MAX_DATAPOINTS);
+    let measurements = storage.data(&id, first, last, combine_datapoints, combine_millis).await?;
+
+    let mut map: HashMap<_, Vec<_>> = HashMap::new();
+    for entry in particles::distribution(&measurements) {
+        map.entry("timestamp").or_default().push(entry.timestamp as f64);
+        for (bin, count) in particles::BINS.iter().zip(entry.bins) {
+            map.entry(*bin).or_default().push(count);
+        }
+        map.entry("fine_coarse_ratio").or_default().push(entry.fine_coarse_ratio);
+        map.entry("pm2_5_pm10_ratio").or_default().push(entry.pm2_5_pm10_ratio);
+        map.entry("typical_size").or_default().push(entry.typical_size);
+    }
+    Ok(Some(Ok(Json(map))))
+}
+
+/// Evaluates the WHO 2021 and EU guidelines against hourly averages of the given range.
+#[rocket::get("/exposure/<device>/<first>/<last>")]
+async fn exposure_report(storage: State<'_, Arc<dyn MeasurementStorage>>, devices: State<'_, Devices>, config: State<'_, ExportConfig>, device: String, first: u64, last: u64) -> Result<Option<Json<Vec<GuidelineReport>>>> {
+    let (_, id) = match resolve(&devices, &device) {
+        Some(device) => device,
+        None => return Ok(None),
+    };
+    const HOUR_MILLIS: u64 = 60 * 60 * 1000;
+    let hourly = storage.data(&id, first, last, HOUR_MILLIS / (2 * 60 * 1000), HOUR_MILLIS).await?;
+    Ok(Some(Json(exposure::report(&hourly, config.timezone()))))
+}
+
+/// Hourly and nightly sound level statistics based on the 2 minute measurements.
+#[rocket::get("/acoustics/<device>/<first>/<last>")]
+async fn acoustics_report(storage: State<'_, Arc<dyn MeasurementStorage>>, devices: State<'_, Devices>, config: State<'_, ExportConfig>, device: String, first: u64, last: u64) -> Result<Option<Json<AcousticReport>>> {
+    let (_, id) = match resolve(&devices, &device) {
+        Some(device) => device,
+        None => return Ok(None),
+    };
+    let measurements = storage.data(&id, first, last, 1, 2 * 60 * 1000).await?;
+    Ok(Some(Json(acoustics::report(&measurements, config.timezone()))))
+}
+
+/// Files on the airQ which failed to download `MAX_ATTEMPTS` times and aren't retried anymore.
+#[rocket::get("/failed-files/<device>")]
+async fn failed_files(storage: State<'_, Arc<dyn MeasurementStorage>>, devices: State<'_, Devices>, device: String) -> Result<Option<Json<Vec<FileSync>>>> {
+    let (_, id) = match resolve(&devices, &device) {
+        Some(device) => device,
+        None => return Ok(None),
+    };
+    let files = storage.unsynced_files(&id).await?.into_iter()
+        .filter(|sync| sync.state == SyncState::Failed && sync.attempts >= fetch_data::MAX_ATTEMPTS)
+        .collect();
+    Ok(Some(Json(files)))
+}
+
+/// Reports which rows the retention rules would delete if they were applied now.
+#[rocket::get("/retention/dry-run")]
+async fn retention_dry_run(storage: State<'_, Arc<dyn MeasurementStorage>>, retention: State<'_, Retention>) -> Result<Json<RetentionReport>> {
+    Ok(Json(storage.apply_retention(&retention.cutoffs(), true).await?))
+}
+
+/// Latest readouts and operational metrics in the Prometheus text format.
+#[rocket::get("/metrics")]
+async fn prometheus_metrics(storage: State<'_, Arc<dyn MeasurementStorage>>, devices: State<'_, Devices>) -> String {
+    metrics::render(&devices, &CURRENT_DATA.lock().unwrap(), &**storage)
+}
+
+async fn fetch_current_data_regularly(device: Arc<Device>, storage: Arc<dyn MeasurementStorage>, mqtt: Option<Arc<Mqtt>>, interval: Duration) {
     loop {
-        match AssertUnwindSafe(fetchdata.fetch_current()).catch_unwind().await {
-            Ok(Err(e)) => eprintln!("Error fetching current data from airQ: {:?}", e),
+        match AssertUnwindSafe(device.fetchdata.fetch_current()).catch_unwind().await {
+            Ok(Err(Error::NotConnected)) => (),
+            Ok(Err(e)) => eprintln!("Error fetching current data from airQ {}: {:?}", device.name, e),
             Ok(Ok(data)) => {
+                match device.set_id(&data.data11.deviceid, &*storage).await {
+                    Ok(()) => (),
+                    Err(e) => eprintln!("couldn't register airQ {}: {e:?}", device.name),
+                }
                 match storage.store_manual_readout(&data).await {
                     Ok(()) => (),
                     Err(e) => eprintln!("couldn't store manual readout: {e:?}"),
                 }
-                *CURRENT_DATA.lock().unwrap() = Some(data);
+                if let Some(mqtt) = &mqtt {
+                    match mqtt.publish(&device.name, &data) {
+                        Ok(()) => (),
+                        Err(e) => eprintln!("couldn't publish readout of airQ {} via MQTT: {e:?}", device.name),
+                    }
+                }
+                CURRENT_DATA.lock().unwrap().insert(data.data11.deviceid.clone(), data);
             },
-            Err(e) => eprintln!("Panic fetching current data from airQ: {:?}", e),
+            Err(e) => eprintln!("Panic fetching current data from airQ {}: {:?}", device.name, e),
         }
-        time::sleep(Duration::from_secs(5)).await;
+        time::sleep(interval).await;
     }
 }
-async fn fetch_data_regularly(airq_ip: String, password: String, storage: Arc<dyn MeasurementStorage>) {
-    let fetchdata = FetchData::new(&airq_ip, &password);
+async fn fetch_data_regularly(device: Arc<Device>, storage: Arc<dyn MeasurementStorage>, interval: Duration) {
     loop {
-        match AssertUnwindSafe(fetchdata.fetch_data(&*storage)).catch_unwind().await {
-            Ok(Err(e)) => eprintln!("Error fetching data from airQ: {:?}", e),
-            Ok(Ok(())) => (),
-            Err(e) => eprintln!("Panic fetching data from airQ: {:?}", e),
+        // the id is only known after the first successful readout
+        if let Some(id) = device.id() {
+            match AssertUnwindSafe(device.fetchdata.fetch_data(&id, &*storage)).catch_unwind().await {
+                Ok(Err(Error::NotConnected)) => (),
+                Ok(Err(e)) => eprintln!("Error fetching data from airQ {}: {:?}", device.name, e),
+                Ok(Ok(())) => (),
+                Err(e) => eprintln!("Panic fetching data from airQ {}: {:?}", device.name, e),
+            }
         }
-        time::sleep(Duration::from_secs(2 * 60)).await;
+        time::sleep(interval).await;
     }
 }
-async fn clean_manual_readouts_regularly(storage: Arc<dyn MeasurementStorage>) {
+/// Finds the airQ via mDNS and finds it again whenever it stops responding, e.g. because it got a new IP.
+async fn discover_regularly(device: Arc<Device>, interval: Duration) {
+    /// number of consecutive failed requests after which the airQ is searched again
+    const MAX_FAILURES: u32 = 10;
     loop {
-        match storage.clean_manual_readouts().await {
-            Ok(()) => (),
-            Err(e) => eprintln!("cleaning manual readouts failed: {:?}", e),
+        if !device.fetchdata.is_connected() || device.fetchdata.failures() >= MAX_FAILURES {
+            match tokio::task::spawn_blocking(|| AirQ::discover(Duration::from_secs(30))).await {
+                Ok(Ok(Some(addr))) => {
+                    println!("Using AirQ {} at {}", device.name, addr);
+                    device.fetchdata.set_address(&addr);
+                },
+                Ok(Ok(None)) => eprintln!("couldn't find an airQ in the network"),
+                Ok(Err(e)) => eprintln!("Error discovering airQ: {:?}", e),
+                Err(e) => eprintln!("Panic discovering airQ: {:?}", e),
+            }
+        }
+        time::sleep(interval).await;
+    }
+}
+async fn apply_retention_regularly(storage: Arc<dyn MeasurementStorage>, retention: Retention) {
+    loop {
+        match storage.apply_retention(&retention.cutoffs(), false).await {
+            Ok(report) => println!("retention: {:?}", report),
+            Err(e) => eprintln!("applying the retention rules failed: {:?}", e),
         }
         // once per day
         time::sleep(Duration::from_secs(24 * 60 * 60)).await;
@@ -147,22 +654,74 @@ async fn rocket() -> rocket::Rocket {
     if cfg!(debug_assertions) {
         let _ = dotenv::dotenv();
     }
-    let storage: Arc<dyn MeasurementStorage> = if cfg!(debug_assertions) {
-        Arc::new(Sevendays::open())
-    } else {
-        Arc::new(Postgres::connect().await)
+    let config = Config::load();
+    // `airq-server migrate` only applies the database migrations
+    if std::env::args().nth(1).as_deref() == Some("migrate") {
+        let (url, timescale) = match &config.storage {
+            StorageConfig::Postgres { url, timescale, .. } => (url, *timescale),
+            _ => panic!("migrate requires the postgres storage backend"),
+        };
+        Postgres::connect(url, timescale, None).await.migrate().await.unwrap();
+        println!("database is up to date");
+        std::process::exit(0);
+    }
+    let storage: Arc<dyn MeasurementStorage> = match &config.storage {
+        StorageConfig::Sevendays { path } => {
+            let legacy_device = match config.devices.as_slice() {
+                [device] => Some(device.name.clone()),
+                _ => None,
+            };
+            Arc::new(Sevendays::open(path, legacy_device))
+        },
+        StorageConfig::Postgres { url, timescale, legacy_device } => {
+            let legacy_device = match (legacy_device, config.devices.as_slice()) {
+                (Some(name), _) => Some(name.clone()),
+                (None, [device]) => Some(device.name.clone()),
+                (None, _) => None,
+            };
+            let postgres = Postgres::connect(url, *timescale, legacy_device).await;
+            postgres.migrate().await.unwrap();
+            postgres.backfill_rollups();
+            Arc::new(postgres)
+        },
+        StorageConfig::Sqlite { path } => Arc::new(Sqlite::open(path).await.unwrap()),
+        StorageConfig::Influxdb(influxdb) => Arc::new(InfluxDb::connect(influxdb).await),
     };
+    // `airq-server benchmark [files] [entries per file]` measures the throughput of a synthetic backfill
+    if std::env::args().nth(1).as_deref() == Some("benchmark") {
+        let arg = |n, default| std::env::args().nth(n).map_or(default, |arg| arg.parse().expect("invalid number"));
+        benchmark::backfill(&*storage, arg(2, 1000), arg(3, 720)).await.unwrap();
+        std::process::exit(0);
+    }
 
-    let airq_ip = AirQ::find_in_network();
-    println!("Using AirQ at {}", airq_ip);
-    let password = std::env::var("AIRQ_PASSWORD").unwrap();
-
-    tokio::spawn(fetch_current_data_regularly(airq_ip.clone(), password.clone(), Arc::clone(&storage)));
-    tokio::spawn(fetch_data_regularly(airq_ip, password, Arc::clone(&storage)));
-    tokio::spawn(clean_manual_readouts_regularly(Arc::clone(&storage)));
+    let devices = Devices::new(&config.devices, &*storage).await.unwrap();
+    let mqtt = config.mqtt.as_ref().map(|mqtt| Arc::new(Mqtt::connect(mqtt)));
+    let intervals = &config.intervals;
+    for device in devices.iter() {
+        match &device.address {
+            Some(addr) => {
+                println!("Using AirQ {} at {}", device.name, addr);
+                device.fetchdata.set_address(addr);
+            },
+            None => { tokio::spawn(discover_regularly(Arc::clone(device), Duration::from_secs(intervals.discover))); },
+        }
+        tokio::spawn(fetch_current_data_regularly(Arc::clone(device), Arc::clone(&storage), mqtt.clone(), Duration::from_secs(intervals.current)));
+        tokio::spawn(fetch_data_regularly(Arc::clone(device), Arc::clone(&storage), Duration::from_secs(intervals.data)));
+    }
+    tokio::spawn(apply_retention_regularly(Arc::clone(&storage), config.retention.clone()));
 
-    let rocket = rocket::ignite()
-        .manage(storage);
+    let mut figment = rocket::Config::figment();
+    if let Some(address) = &config.server.address {
+        figment = figment.merge(("address", address));
+    }
+    if let Some(port) = config.server.port {
+        figment = figment.merge(("port", port));
+    }
+    let rocket = rocket::custom(figment)
+        .manage(config.retention)
+        .manage(config.export)
+        .manage(storage)
+        .manage(devices);
     // static files
     let rocket = if cfg!(debug_assertions) {
         rocket.mount("/", StaticFiles::from("static/"))
@@ -170,5 +729,5 @@ async fn rocket() -> rocket::Rocket {
         rocket.mount("/", IncludedStaticFiles)
     };
     // routes
-    rocket.mount("/", rocket::routes![timestamps, data_current, data])
+    rocket.mount("/", rocket::routes![devices, timestamps, data_current, data, data_raw, data_occupancy, data_infection_risk, data_pressure_tendency, particle_distribution, exposure_report, acoustics_report, failed_files, retention_dry_run, export_file, prometheus_metrics])
 }
@@ -0,0 +1,140 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use airq::Data14;
+use serde_json::Value;
+
+use crate::device::Devices;
+use crate::storage::MeasurementStorage;
+
+/// Sum and count of observed durations, exported as Prometheus summary without quantiles.
+#[derive(Default)]
+pub struct Summary {
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Summary {
+    pub fn observe(&self, duration: Duration) {
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Operational metrics of the requests to a single airQ.
+#[derive(Default)]
+pub struct FetchMetrics {
+    pub fetch_current_duration: Summary,
+    pub fetch_data_duration: Summary,
+    /// number of failed requests by `airq::Error::kind`
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+    /// unix timestamp in seconds of the last successful `fetch_data`
+    pub last_fetch_data_success: AtomicU64,
+    pub rows_inserted: AtomicU64,
+}
+
+impl FetchMetrics {
+    pub fn error(&self, error: &airq::Error) {
+        *self.errors.lock().unwrap().entry(error.kind()).or_default() += 1;
+    }
+}
+
+/// Renders the latest readouts and operational metrics in the Prometheus text format.
+pub fn render(devices: &Devices, current_data: &HashMap<String, Data14>, storage: &dyn MeasurementStorage) -> String {
+    let mut out = String::new();
+
+    // one gauge per numeric field of the readout, of values with uncertainty only the value
+    let mut sensors: BTreeMap<String, Vec<(String, f64)>> = BTreeMap::new();
+    for device in devices.iter() {
+        let id = match device.id() {
+            Some(id) => id,
+            None => continue,
+        };
+        let data = match current_data.get(&id) {
+            Some(data) => data,
+            None => continue,
+        };
+        let labels = format!("device=\"{}\",id=\"{}\"", escape(&device.name), escape(&id));
+        let fields = match serde_json::to_value(data) {
+            Ok(Value::Object(fields)) => fields,
+            _ => continue,
+        };
+        for (field, value) in fields {
+            let value = match value {
+                Value::Array(values) => values.first().and_then(Value::as_f64),
+                value => value.as_f64(),
+            };
+            if let Some(value) = value {
+                sensors.entry(format!("airq_{}", metric_name(&field))).or_default().push((labels.clone(), value));
+            }
+        }
+    }
+    for (name, samples) in sensors {
+        writeln!(out, "# TYPE {} gauge", name).unwrap();
+        for (labels, value) in samples {
+            writeln!(out, "{}{{{}}} {}", name, labels, value).unwrap();
+        }
+    }
+
+    let devices: Vec<_> = devices.iter().collect();
+    let label = |name: &str| format!("device=\"{}\"", escape(name));
+
+    writeln!(out, "# HELP airq_fetch_duration_seconds Duration of requests to the airQ.").unwrap();
+    writeln!(out, "# TYPE airq_fetch_duration_seconds summary").unwrap();
+    for device in &devices {
+        let metrics = &device.fetchdata.metrics;
+        for (kind, summary) in [("current", &metrics.fetch_current_duration), ("data", &metrics.fetch_data_duration)] {
+            let labels = format!("{},kind=\"{}\"", label(&device.name), kind);
+            let sum = summary.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+            writeln!(out, "airq_fetch_duration_seconds_sum{{{}}} {}", labels, sum).unwrap();
+            writeln!(out, "airq_fetch_duration_seconds_count{{{}}} {}", labels, summary.count.load(Ordering::Relaxed)).unwrap();
+        }
+    }
+
+    writeln!(out, "# HELP airq_request_errors_total Failed requests to the airQ by kind of error.").unwrap();
+    writeln!(out, "# TYPE airq_request_errors_total counter").unwrap();
+    for device in &devices {
+        for (kind, count) in device.fetchdata.metrics.errors.lock().unwrap().iter() {
+            writeln!(out, "airq_request_errors_total{{{},kind=\"{}\"}} {}", label(&device.name), kind, count).unwrap();
+        }
+    }
+
+    writeln!(out, "# HELP airq_last_fetch_data_success_timestamp_seconds Time of the last successful download of the stored measurements.").unwrap();
+    writeln!(out, "# TYPE airq_last_fetch_data_success_timestamp_seconds gauge").unwrap();
+    for device in &devices {
+        let timestamp = device.fetchdata.metrics.last_fetch_data_success.load(Ordering::Relaxed);
+        writeln!(out, "airq_last_fetch_data_success_timestamp_seconds{{{}}} {}", label(&device.name), timestamp).unwrap();
+    }
+
+    writeln!(out, "# HELP airq_rows_inserted_total Measurements downloaded from the airQ and stored.").unwrap();
+    writeln!(out, "# TYPE airq_rows_inserted_total counter").unwrap();
+    for device in &devices {
+        let rows = device.fetchdata.metrics.rows_inserted.load(Ordering::Relaxed);
+        writeln!(out, "airq_rows_inserted_total{{{}}} {}", label(&device.name), rows).unwrap();
+    }
+
+    if let Some((size, idle)) = storage.pool_stats() {
+        writeln!(out, "# HELP airq_db_pool_connections Open database connections.").unwrap();
+        writeln!(out, "# TYPE airq_db_pool_connections gauge").unwrap();
+        writeln!(out, "airq_db_pool_connections {}", size).unwrap();
+        writeln!(out, "# HELP airq_db_pool_idle_connections Idle database connections.").unwrap();
+        writeln!(out, "# TYPE airq_db_pool_idle_connections gauge").unwrap();
+        writeln!(out, "airq_db_pool_idle_connections {}", idle).unwrap();
+    }
+
+    out
+}
+
+/// Converts a field name of the airQ like `dCO2dt` to a valid metric name like `dco2dt`.
+fn metric_name(field: &str) -> String {
+    field.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect()
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,208 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use airq::Data14;
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use serde_json::json;
+use tokio::time;
+
+use crate::config::MqttConfig;
+use crate::fetch_data::Error;
+
+struct Sensor {
+    field: &'static str,
+    name: &'static str,
+    /// Home Assistant device class, if there is one matching the unit
+    device_class: Option<&'static str>,
+    unit: Option<&'static str>,
+    value: fn(&Data14) -> Option<f64>,
+}
+
+const SENSORS: &[Sensor] = &[
+    Sensor { field: "health", name: "Health", device_class: None, unit: None, value: |d| Some(d.data11.health) },
+    Sensor { field: "performance", name: "Performance", device_class: None, unit: None, value: |d| Some(d.data11.performance) },
+    Sensor { field: "tvoc", name: "TVOC", device_class: Some("volatile_organic_compounds_parts"), unit: Some("ppb"), value: |d| Some(d.data11.tvoc?[0]) },
+    Sensor { field: "humidity", name: "Humidity", device_class: Some("humidity"), unit: Some("%"), value: |d| Some(d.data11.humidity[0]) },
+    Sensor { field: "humidity_abs", name: "Absolute Humidity", device_class: None, unit: Some("g/m³"), value: |d| Some(d.data11.humidity_abs[0]) },
+    Sensor { field: "temperature", name: "Temperature", device_class: Some("temperature"), unit: Some("°C"), value: |d| Some(d.data11.temperature[0]) },
+    Sensor { field: "dewpt", name: "Dew Point", device_class: Some("temperature"), unit: Some("°C"), value: |d| Some(d.data11.dewpt[0]) },
+    Sensor { field: "sound", name: "Noise", device_class: Some("sound_pressure"), unit: Some("dBA"), value: |d| Some(d.data11.sound[0]) },
+    Sensor { field: "pressure", name: "Pressure", device_class: Some("atmospheric_pressure"), unit: Some("hPa"), value: |d| Some(d.data11.pressure[0]) },
+    Sensor { field: "no2", name: "NO₂", device_class: Some("nitrogen_dioxide"), unit: Some("µg/m³"), value: |d| Some(d.data11.no2?[0]) },
+    // Home Assistant expects carbon monoxide in ppm
+    Sensor { field: "co", name: "CO", device_class: None, unit: Some("mg/m³"), value: |d| Some(d.data11.co?[0]) },
+    Sensor { field: "co2", name: "CO₂", device_class: Some("carbon_dioxide"), unit: Some("ppm"), value: |d| Some(d.data11.co2[0]) },
+    Sensor { field: "pm1", name: "PM1", device_class: Some("pm1"), unit: Some("µg/m³"), value: |d| Some(d.data11.pm1[0]) },
+    Sensor { field: "pm2_5", name: "PM2.5", device_class: Some("pm25"), unit: Some("µg/m³"), value: |d| Some(d.data11.pm2_5[0]) },
+    Sensor { field: "pm10", name: "PM10", device_class: Some("pm10"), unit: Some("µg/m³"), value: |d| Some(d.data11.pm10[0]) },
+    Sensor { field: "oxygen", name: "Oxygen", device_class: None, unit: Some("%"), value: |d| Some(d.oxygen[0]) },
+    Sensor { field: "o3", name: "O₃", device_class: Some("ozone"), unit: Some("µg/m³"), value: |d| Some(d.o3?[0]) },
+    Sensor { field: "so2", name: "SO₂", device_class: Some("sulphur_dioxide"), unit: Some("µg/m³"), value: |d| Some(d.so2?[0]) },
+];
+
+/// Publishes readouts to an MQTT broker, including Home Assistant discovery messages.
+pub struct Mqtt {
+    client: AsyncClient,
+    /// prefix of the state topics `<prefix>/<device>/<field>`
+    prefix: String,
+    discovery_prefix: String,
+    /// ids of the devices whose discovery messages were published since the last (re)connect
+    /// or since Home Assistant came online
+    announced: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Mqtt {
+    pub fn connect(config: &MqttConfig) -> Mqtt {
+        let mut options = MqttOptions::new("airq-server", &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            options.set_credentials(username, password);
+        }
+
+        let (client, mut eventloop) = AsyncClient::new(options, 64);
+        let announced = Arc::new(Mutex::new(HashSet::new()));
+        // Home Assistant publishes `online` to its status topic when it starts
+        let status_topic = format!("{}/status", config.discovery_prefix);
+        // the event loop sends the published messages and reconnects on errors
+        tokio::spawn({
+            let client = client.clone();
+            let announced = Arc::clone(&announced);
+            async move {
+                loop {
+                    match eventloop.poll().await {
+                        // the broker may have lost the retained discovery messages, announce the devices again
+                        Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                            announced.lock().unwrap().clear();
+                            if let Err(e) = client.try_subscribe(&status_topic, QoS::AtLeastOnce) {
+                                eprintln!("couldn't subscribe to {}: {:?}", status_topic, e);
+                            }
+                        },
+                        Ok(Event::Incoming(Packet::Publish(publish))) if publish.topic == status_topic && &publish.payload[..] == b"online" => {
+                            announced.lock().unwrap().clear();
+                        },
+                        Ok(_) => (),
+                        Err(e) => {
+                            eprintln!("MQTT connection error: {:?}", e);
+                            time::sleep(Duration::from_secs(5)).await;
+                        },
+                    }
+                }
+            }
+        });
+
+        Mqtt {
+            client,
+            prefix: config.prefix.clone(),
+            discovery_prefix: config.discovery_prefix.clone(),
+            announced,
+        }
+    }
+
+    /// Publishes each sensor of the readout as retained state,
+    /// preceded by the discovery messages the first time a device is published.
+    ///
+    /// Doesn't wait for the broker: while it isn't reachable the queue of the client fills up
+    /// and the readout is dropped with an error instead of blocking the readouts.
+    pub fn publish(&self, device: &str, data: &Data14) -> Result<(), Error> {
+        let id = &data.data11.deviceid;
+        let announced = self.announced.lock().unwrap().contains(id);
+        if !announced {
+            for sensor in SENSORS {
+                self.announce(device, id, sensor)?;
+            }
+            self.announced.lock().unwrap().insert(id.clone());
+        }
+        for sensor in SENSORS {
+            if let Some(value) = (sensor.value)(data) {
+                self.client.try_publish(self.state_topic(device, sensor), QoS::AtLeastOnce, true, value.to_string())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn announce(&self, device: &str, id: &str, sensor: &Sensor) -> Result<(), Error> {
+        let mut config = json!({
+            "name": sensor.name,
+            "unique_id": format!("{}_{}", id, sensor.field),
+            "state_topic": self.state_topic(device, sensor),
+            "state_class": "measurement",
+            "device": {
+                "identifiers": [id],
+                "name": device,
+                "manufacturer": "Corant",
+                "model": "air-Q",
+            },
+        });
+        if let Some(device_class) = sensor.device_class {
+            config["device_class"] = json!(device_class);
+        }
+        if let Some(unit) = sensor.unit {
+            config["unit_of_measurement"] = json!(unit);
+        }
+        let topic = format!("{}/sensor/{}/{}/config", self.discovery_prefix, id, sensor.field);
+        self.client.try_publish(topic, QoS::AtLeastOnce, true, config.to_string())?;
+        Ok(())
+    }
+
+    fn state_topic(&self, device: &str, sensor: &Sensor) -> String {
+        format!("{}/{}/{}", self.prefix, device, sensor.field)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Needs a broker, e.g. `mosquitto`, at `MQTT_TEST_HOST` (default localhost):
+    /// `cargo test -p server -- --ignored mqtt`
+    #[rocket::async_test]
+    #[ignore]
+    async fn publishes_discovery_and_state() {
+        let config = MqttConfig {
+            host: std::env::var("MQTT_TEST_HOST").unwrap_or_else(|_| "localhost".to_string()),
+            port: 1883,
+            username: None,
+            password: None,
+            prefix: "airq-test".to_string(),
+            discovery_prefix: "airq-test-discovery".to_string(),
+        };
+        let data: Data14 = serde_json::from_value(json!({
+            "DeviceID": "0123456789abcdef", "Status": "OK", "uptime": 3600, "health": 900.0, "performance": 800.0,
+            "measuretime": 2000.0, "timestamp": 1_600_000_000_000u64, "bat": null, "door_event": null, "window_open": null,
+            "tvoc": [100.0, 10.0], "humidity": [45.0, 3.0], "humidity_abs": [9.0, 1.0], "dHdt": null,
+            "temperature": [21.5, 0.5], "dewpt": [9.3, 1.0], "sound": [40.0, 3.0], "pressure": [1013.0, 1.0],
+            "no2": null, "co": null, "co2": [612.0, 30.0], "dCO2dt": null, "pm1": [1.0, 1.0], "pm2_5": [2.0, 1.0],
+            "pm10": [3.0, 1.0], "cnt0_3": [100.0, 10.0], "cnt0_5": [50.0, 10.0], "cnt1": [10.0, 5.0], "cnt2_5": [1.0, 1.0],
+            "cnt5": [0.0, 1.0], "cnt10": [0.0, 1.0], "TypPS": 0.5, "oxygen": [20.9, 1.0], "o3": null, "so2": null,
+        })).unwrap();
+
+        let mut options = MqttOptions::new("airq-server-test", &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        let (subscriber, mut eventloop) = AsyncClient::new(options, 16);
+        subscriber.subscribe("airq-test/#", QoS::AtLeastOnce).await.unwrap();
+        subscriber.subscribe("airq-test-discovery/#", QoS::AtLeastOnce).await.unwrap();
+
+        let mqtt = Mqtt::connect(&config);
+        mqtt.publish("test", &data).unwrap();
+
+        let (mut state, mut discovery) = (None, None);
+        time::timeout(Duration::from_secs(10), async {
+            while state.is_none() || discovery.is_none() {
+                if let Event::Incoming(Packet::Publish(publish)) = eventloop.poll().await.unwrap() {
+                    let payload = String::from_utf8(publish.payload.to_vec()).unwrap();
+                    match publish.topic.as_str() {
+                        "airq-test/test/co2" => state = Some(payload),
+                        "airq-test-discovery/sensor/0123456789abcdef/co2/config" => discovery = Some(payload),
+                        _ => (),
+                    }
+                }
+            }
+        }).await.expect("no messages received from the broker");
+
+        assert_eq!(state.unwrap(), "612");
+        let discovery: serde_json::Value = serde_json::from_str(&discovery.unwrap()).unwrap();
+        assert_eq!(discovery["state_topic"], "airq-test/test/co2");
+        assert_eq!(discovery["device_class"], "carbon_dioxide");
+    }
+}
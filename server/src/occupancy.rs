@@ -0,0 +1,98 @@
+use crate::Measurement;
+use crate::room::Room;
+
+pub struct Occupancy {
+    pub timestamp: i64,
+    /// estimated number of people in the room
+    pub people: f64,
+    pub occupied: bool,
+}
+
+/// Estimates the room occupancy from consecutive CO₂ and sound measurements.
+///
+/// The number of people follows from the CO₂ mass balance of the room
+/// `V·dC/dt = n·G - Q·(C - C_out)` with the air exchange `Q = ACH·V`.
+/// The room counts as occupied if at least half a person is estimated,
+/// or if it is loud while CO₂ is rising.
+/// The CO₂ slope is taken from the change rate reported by the airQ if available,
+/// as it isn't distorted by gaps between the measurements.
+pub fn estimate(room: &Room, measurements: &[Measurement]) -> Vec<Occupancy> {
+    measurements.windows(2)
+        .filter_map(|window| {
+            let (prev, cur) = (&window[0], &window[1]);
+            let hours = (cur.timestamp? - prev.timestamp?) as f64 / (60. * 60. * 1000.);
+            if hours <= 0. {
+                return None;
+            }
+            let co2 = cur.co2?;
+            // dCO2dt is reported in ppm/s
+            let slope = match cur.co2_delta.filter(|delta| delta.is_finite()) {
+                Some(delta) => delta * 60. * 60.,
+                None => (co2 - prev.co2?) / hours,
+            };
+            // ppm·m³ = 1e-3 l
+            let generated = room.volume * (slope + room.air_change_rate * (co2 - room.outdoor_co2)) * 1e-3;
+            let people = (generated / room.co2_per_person).max(0.);
+            let loud = matches!(cur.sound, Some(sound) if sound >= room.sound_threshold);
+            Some(Occupancy {
+                timestamp: cur.timestamp?,
+                people,
+                occupied: people >= 0.5 || (loud && slope > 0.),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    fn measurements(readouts: &[(f64, Option<f64>, f64)]) -> Vec<Measurement> {
+        readouts.iter()
+            .enumerate()
+            .map(|(i, &(co2, co2_delta, sound))| serde_json::from_value(json!({
+                "timestamp": i as i64 * 60 * 60 * 1000,
+                "co2": co2,
+                "co2_delta": co2_delta,
+                "sound": sound,
+            })).unwrap())
+            .collect()
+    }
+
+    fn people(occupancy: &[Occupancy]) -> Vec<f64> {
+        occupancy.iter().map(|occupancy| (occupancy.people * 100.).round() / 100.).collect()
+    }
+
+    #[test]
+    fn steady_state() {
+        // 50 m³ exchanged at 0.5/h need 720 ppm above outdoors to remove the 18 l/h of one person
+        let room = Room::default();
+        let occupancy = estimate(&room, &measurements(&[(420., None, 30.), (420., None, 30.), (1140., Some(0.), 30.), (1140., None, 30.)]));
+        assert_eq!(people(&occupancy), vec![0., 1., 1.]);
+        assert_eq!(occupancy.iter().map(|occupancy| occupancy.occupied).collect::<Vec<_>>(), vec![false, true, true]);
+    }
+
+    #[test]
+    fn reported_change_rate() {
+        let room = Room::default();
+        // derived from consecutive measurements without the change rate
+        let occupancy = estimate(&room, &measurements(&[(1140., None, 30.), (1500., None, 30.)]));
+        assert_eq!(people(&occupancy), vec![2.5]);
+        // 0.1 ppm/s are 360 ppm/h, the slope of a second person
+        let occupancy = estimate(&room, &measurements(&[(1140., None, 30.), (1140., Some(0.1), 30.)]));
+        assert_eq!(people(&occupancy), vec![2.]);
+    }
+
+    #[test]
+    fn ventilation_and_noise() {
+        let room = Room::default();
+        // airing out doesn't make negative people
+        let occupancy = estimate(&room, &measurements(&[(1500., None, 30.), (500., None, 30.)]));
+        assert_eq!(people(&occupancy), vec![0.]);
+        assert!(!occupancy[0].occupied);
+        // slightly rising CO₂ counts as occupied if it is loud
+        let occupancy = estimate(&room, &measurements(&[(420., None, 30.), (430., None, 50.), (440., None, 30.)]));
+        assert_eq!(occupancy.iter().map(|occupancy| occupancy.occupied).collect::<Vec<_>>(), vec![true, false]);
+    }
+}
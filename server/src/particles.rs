@@ -0,0 +1,90 @@
+use crate::Measurement;
+
+/// names of the particle size bins in μm
+pub const BINS: [&str; 6] = ["0.3-0.5", "0.5-1", "1-2.5", "2.5-5", "5-10", ">10"];
+
+pub struct Distribution {
+    pub timestamp: i64,
+    /// number of particles in each of the `BINS`
+    pub bins: [f64; 6],
+    /// ratio of the number of particles smaller than 2.5 μm to larger ones,
+    /// high for combustion particles like cooking smoke and low for dust and pollen
+    pub fine_coarse_ratio: f64,
+    /// ratio of the PM2.5 to the PM10 mass concentration
+    pub pm2_5_pm10_ratio: f64,
+    /// typical particle size in μm
+    pub typical_size: f64,
+}
+
+/// Derives the particle size distribution from the particle counts.
+///
+/// The counts of the airQ are cumulative, e.g. `cnt1` counts all particles larger than 1 μm,
+/// so the number of particles within a bin is the difference to the count of the next larger size.
+/// Ratios are NaN if the denominator is zero.
+pub fn distribution(measurements: &[Measurement]) -> Vec<Distribution> {
+    measurements.iter()
+        .filter_map(|m| {
+            let counts = [m.cnt0_3?, m.cnt0_5?, m.cnt1?, m.cnt2_5?, m.cnt5?, m.cnt10?];
+            let mut bins = [0.; 6];
+            for (i, bin) in bins.iter_mut().enumerate() {
+                let larger = counts.get(i + 1).copied().unwrap_or(0.);
+                // averaged counts may not be strictly decreasing
+                *bin = (counts[i] - larger).max(0.);
+            }
+            let coarse = counts[3];
+            let fine = (counts[0] - coarse).max(0.);
+            Some(Distribution {
+                timestamp: m.timestamp?,
+                bins,
+                fine_coarse_ratio: if coarse > 0. { fine / coarse } else { f64::NAN },
+                pm2_5_pm10_ratio: match (m.pm2_5, m.pm10) {
+                    (Some(pm2_5), Some(pm10)) if pm10 > 0. => pm2_5 / pm10,
+                    _ => f64::NAN,
+                },
+                typical_size: m.typ_ps.unwrap_or(f64::NAN),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    fn measurement(counts: [f64; 6], pm2_5: f64, pm10: f64) -> Measurement {
+        serde_json::from_value(json!({
+            "timestamp": 1000,
+            "cnt0_3": counts[0], "cnt0_5": counts[1], "cnt1": counts[2], "cnt2_5": counts[3], "cnt5": counts[4], "cnt10": counts[5],
+            "pm2_5": pm2_5, "pm10": pm10, "typ_ps": 0.6,
+        })).unwrap()
+    }
+
+    #[test]
+    fn cumulative_counts_are_binned() {
+        let particles = distribution(&[measurement([100., 60., 30., 10., 4., 1.], 10., 20.)]);
+        assert_eq!(particles.len(), 1);
+        assert_eq!(particles[0].timestamp, 1000);
+        assert_eq!(particles[0].bins, [40., 30., 20., 6., 3., 1.]);
+        assert_eq!(particles[0].fine_coarse_ratio, 9.);
+        assert_eq!(particles[0].pm2_5_pm10_ratio, 0.5);
+        assert_eq!(particles[0].typical_size, 0.6);
+    }
+
+    #[test]
+    fn averaged_counts_increasing_with_size_are_clamped() {
+        let particles = distribution(&[measurement([5., 8., 3., 6., 1., 0.], 0., 0.)]);
+        assert_eq!(particles[0].bins, [0., 5., 0., 5., 1., 0.]);
+        assert_eq!(particles[0].fine_coarse_ratio, 0.);
+        assert!(particles[0].pm2_5_pm10_ratio.is_nan());
+
+        let without_coarse = distribution(&[measurement([5., 2., 1., 0., 0., 0.], 1., 2.)]);
+        assert!(without_coarse[0].fine_coarse_ratio.is_nan());
+    }
+
+    #[test]
+    fn incomplete_counts_are_skipped() {
+        let measurement: Measurement = serde_json::from_value(json!({ "timestamp": 1000, "cnt0_3": 5. })).unwrap();
+        assert!(distribution(&[measurement]).is_empty());
+    }
+}
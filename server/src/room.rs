@@ -0,0 +1,108 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::config::override_var;
+
+/// Physical properties of the room the airQ is placed in.
+///
+/// Needed for series derived from the CO₂ mass balance of the room.
+/// Configured per device, falling back to an average office room.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Room {
+    /// room volume in m³
+    pub volume: f64,
+    /// CO₂ exhaled per person in l/h (~18 l/h for an adult at rest)
+    pub co2_per_person: f64,
+    /// air changes per hour when the room is not actively ventilated
+    pub air_change_rate: f64,
+    /// outdoor CO₂ concentration in ppm
+    pub outdoor_co2: f64,
+    /// sound level in dB(A) above which the room is considered to be in use
+    pub sound_threshold: f64,
+    /// number of people usually in the room
+    pub occupants: f64,
+    pub activity: Activity,
+    /// altitude of the room above sea level in m
+    pub altitude: f64,
+}
+
+/// What people in the room are usually doing, determining how many infectious aerosols they emit.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Activity {
+    Resting,
+    Talking,
+    LightExercise,
+    Singing,
+    HeavyExercise,
+}
+
+impl Activity {
+    /// quanta per hour emitted by an infectious person (rough values following Buonanno et al. 2020)
+    pub fn quanta_emission_rate(self) -> f64 {
+        match self {
+            Activity::Resting => 1.,
+            Activity::Talking => 10.,
+            Activity::LightExercise => 20.,
+            Activity::Singing => 60.,
+            Activity::HeavyExercise => 100.,
+        }
+    }
+}
+
+impl FromStr for Activity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "resting" => Ok(Activity::Resting),
+            "talking" => Ok(Activity::Talking),
+            "light-exercise" => Ok(Activity::LightExercise),
+            "singing" => Ok(Activity::Singing),
+            "heavy-exercise" => Ok(Activity::HeavyExercise),
+            _ => Err(format!("unknown activity {:?}", s)),
+        }
+    }
+}
+
+impl Default for Room {
+    fn default() -> Self {
+        Room {
+            volume: 50.,
+            co2_per_person: 18.,
+            air_change_rate: 0.5,
+            outdoor_co2: 420.,
+            sound_threshold: 45.,
+            occupants: 2.,
+            activity: Activity::Talking,
+            altitude: 0.,
+        }
+    }
+}
+
+impl Room {
+    /// Overrides the room of the given device from `AIRQ_<DEVICE>_ROOM_*`,
+    /// falling back to `ROOM_*` if it is the only device.
+    pub fn apply_env(&mut self, device: &str, single: bool) {
+        Self::var(&mut self.volume, device, "ROOM_VOLUME", single);
+        Self::var(&mut self.co2_per_person, device, "ROOM_CO2_PER_PERSON", single);
+        Self::var(&mut self.air_change_rate, device, "ROOM_AIR_CHANGE_RATE", single);
+        Self::var(&mut self.outdoor_co2, device, "ROOM_OUTDOOR_CO2", single);
+        Self::var(&mut self.sound_threshold, device, "ROOM_SOUND_THRESHOLD", single);
+        Self::var(&mut self.occupants, device, "ROOM_OCCUPANTS", single);
+        Self::var(&mut self.activity, device, "ROOM_ACTIVITY", single);
+        Self::var(&mut self.altitude, device, "ROOM_ALTITUDE", single);
+    }
+
+    fn var<T: FromStr>(value: &mut T, device: &str, name: &str, single: bool) where T::Err: Display {
+        let prefixed = format!("AIRQ_{}_{}", device.to_uppercase(), name);
+        if std::env::var(&prefixed).is_ok() {
+            override_var(value, &prefixed, |value| value);
+        } else if single {
+            override_var(value, name, |value| value);
+        }
+    }
+}
@@ -0,0 +1,771 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use async_trait::async_trait;
+use chrono::Utc;
+use futures::{Stream, StreamExt};
+use airq::{Data14, FilePath};
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::sync::mpsc;
+use crate::{Timestamps, Measurement};
+use crate::config::InfluxDbConfig;
+use crate::fetch_data::Error;
+use super::{Aggregation, FLOAT_FIELDS, FileSync, MeasurementStorage, RetentionCutoffs, RetentionReport, SyncState, is_selected, needs_rest, resume_from};
+
+/// rows per query of `raw_rows`
+const RAW_CHUNK: usize = 10_000;
+
+/// Stores measurements in InfluxDB 1.x via its HTTP API.
+///
+/// Each readout is a point of the measurement `measurements` with the tags `device` and `persistent`.
+/// A downloaded readout replaces a manual readout of the same time.
+/// Fields the server doesn't know by name are stored as `rest.<name>`.
+/// Sound levels are additionally stored as `sound_energy` to average them energetically.
+/// Points replacing downsampled readouts have the field `samples` with the number of readouts they average,
+/// which weights them when they are downsampled again. Queries average the points unweighted,
+/// as InfluxQL can't weight an aggregate by another field.
+///
+/// The download state of each file is a point of the measurement `file_sync` at the time of the file,
+/// which is overwritten on every state change.
+pub struct InfluxDb {
+    client: reqwest::Client,
+    url: String,
+    database: String,
+    credentials: Option<(String, String)>,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    #[serde(default)]
+    results: Vec<StatementResult>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StatementResult {
+    #[serde(default)]
+    series: Vec<Series>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Series {
+    #[serde(default)]
+    tags: HashMap<String, String>,
+    columns: Vec<String>,
+    values: Vec<Vec<Value>>,
+}
+
+impl InfluxDb {
+    /// Connects to the InfluxDB and creates the database if it doesn't exist yet.
+    pub async fn connect(config: &InfluxDbConfig) -> InfluxDb {
+        let influxdb = InfluxDb {
+            client: reqwest::Client::new(),
+            url: config.url.trim_end_matches('/').to_string(),
+            database: config.database.clone(),
+            credentials: config.username.clone().zip(config.password.clone()),
+        };
+        influxdb.query(&format!("CREATE DATABASE \"{}\"", influxdb.database)).await.unwrap();
+        influxdb
+    }
+
+    fn request(&self, path: &str) -> reqwest::RequestBuilder {
+        let request = self.client.post(format!("{}{}", self.url, path));
+        match &self.credentials {
+            Some((username, password)) => request.basic_auth(username, Some(password)),
+            None => request,
+        }
+    }
+
+    /// Executes InfluxQL statements separated by `;` and returns the series of each statement.
+    async fn query(&self, query: &str) -> Result<Vec<Vec<Series>>, Error> {
+        let response: Response = self.request("/query")
+            .query(&[("db", self.database.as_str()), ("epoch", "ms")])
+            .form(&[("q", query)])
+            .send().await?
+            .json().await?;
+        if let Some(error) = response.error {
+            return Err(Error::InfluxDb(error));
+        }
+        response.results.into_iter()
+            .map(|result| match result.error {
+                Some(error) => Err(Error::InfluxDb(error)),
+                None => Ok(result.series),
+            })
+            .collect()
+    }
+
+    async fn write(&self, lines: &str) -> Result<(), Error> {
+        let response = self.request("/write")
+            .query(&[("db", self.database.as_str()), ("precision", "ms")])
+            .body(lines.to_string())
+            .send().await?;
+        if !response.status().is_success() {
+            return Err(Error::InfluxDb(response.text().await?));
+        }
+        Ok(())
+    }
+
+    /// Aggregates the measurements in buckets of `combine_millis` with the aggregation.
+    async fn aggregated(&self, device: &str, first: u64, last: u64, combine_millis: u64, aggregation: Aggregation, fields: Option<&[String]>) -> Result<Vec<Measurement>, Error> {
+        let (columns, prefix) = aggregate_columns(aggregation, fields);
+        let mut results = self.query(&format!(
+            "SELECT {} FROM measurements WHERE device = {} AND time >= {}ms AND time <= {}ms GROUP BY time({}ms) fill(none)",
+            columns, quote(device), first, last, combine_millis,
+        )).await?;
+        let series = match results.pop().and_then(|mut series| series.pop()) {
+            Some(series) => series,
+            None => return Ok(Vec::new()),
+        };
+
+        Ok(measurements(&series, prefix, aggregation == Aggregation::Avg))
+    }
+}
+
+/// Formats a readout as line protocol.
+fn line(data: &Data14, persistent: bool) -> String {
+    let d = &data.data11;
+    let mut fields = vec![
+        ("health".to_string(), Some(d.health)),
+        ("performance".to_string(), Some(d.performance)),
+        ("tvoc".to_string(), d.tvoc.map(|tvoc| tvoc[0])),
+        ("humidity".to_string(), Some(d.humidity[0])),
+        ("humidity_abs".to_string(), Some(d.humidity_abs[0])),
+        ("temperature".to_string(), Some(d.temperature[0])),
+        ("dewpt".to_string(), Some(d.dewpt[0])),
+        ("sound".to_string(), Some(d.sound[0])),
+        ("sound_energy".to_string(), Some(10f64.powf(d.sound[0] / 10.))),
+        ("pressure".to_string(), Some(d.pressure[0])),
+        ("no2".to_string(), d.no2.map(|no2| no2[0])),
+        ("co".to_string(), d.co.map(|co| co[0])),
+        ("co2".to_string(), Some(d.co2[0])),
+        ("pm1".to_string(), Some(d.pm1[0])),
+        ("pm2_5".to_string(), Some(d.pm2_5[0])),
+        ("pm10".to_string(), Some(d.pm10[0])),
+        ("oxygen".to_string(), Some(data.oxygen[0])),
+        ("o3".to_string(), data.o3.map(|o3| o3[0])),
+        ("so2".to_string(), data.so2.map(|so2| so2[0])),
+        ("uptime".to_string(), Some(d.uptime as f64)),
+        ("measuretime".to_string(), Some(d.measuretime)),
+        ("bat".to_string(), d.bat.map(|bat| bat[0])),
+        ("door_event".to_string(), d.door_event),
+        ("window_open".to_string(), d.window_open),
+        ("humidity_abs_delta".to_string(), d.humidity_abs_delta),
+        ("co2_delta".to_string(), d.co2_delta),
+        ("cnt0_3".to_string(), Some(d.cnt0_3[0])),
+        ("cnt0_5".to_string(), Some(d.cnt0_5[0])),
+        ("cnt1".to_string(), Some(d.cnt1[0])),
+        ("cnt2_5".to_string(), Some(d.cnt2_5[0])),
+        ("cnt5".to_string(), Some(d.cnt5[0])),
+        ("cnt10".to_string(), Some(d.cnt10[0])),
+        ("typ_ps".to_string(), Some(d.typ_ps)),
+    ];
+    for (key, value) in &d.rest {
+        let value = match value {
+            Value::Array(values) => values.first().and_then(Value::as_f64),
+            value => value.as_f64(),
+        };
+        fields.push((format!("rest.{}", key), value));
+    }
+
+    let mut fields: Vec<_> = fields.into_iter()
+        .filter_map(|(key, value)| Some((key, value.filter(|value| value.is_finite())?)))
+        .map(|(key, value)| format!("{}={}", escape_key(&key), value))
+        .collect();
+    let status = serde_json::to_string(&d.status).unwrap();
+    fields.push(format!("status={}", quote_field(&status)));
+    format!(
+        "measurements,device={},persistent={} {} {}",
+        escape_key(&d.deviceid), persistent, fields.join(","), d.timestamp,
+    )
+}
+
+/// Weighted sums of the fields of the points in a bucket to downsample.
+#[derive(Default)]
+struct Downsampled {
+    /// readouts the points of the bucket stand for
+    samples: u64,
+    uptime: Option<f64>,
+    /// sum of the values weighted by their samples and the samples of the points with a value, by field
+    fields: HashMap<String, (f64, u64)>,
+}
+
+impl Downsampled {
+    /// Adds a row of a series of all fields, whose first column is the time.
+    ///
+    /// Points without `samples` are single readouts, downsampled points count as the readouts they average.
+    fn add(&mut self, columns: &[String], values: &[Value]) {
+        let samples = columns.iter().position(|column| column == "samples")
+            .and_then(|index| values.get(index)?.as_u64())
+            .unwrap_or(1);
+        self.samples += samples;
+        for (column, value) in columns.iter().zip(values).skip(1) {
+            // tags and `status` aren't numbers
+            let value = match value.as_f64() {
+                Some(value) if value.is_finite() => value,
+                _ => continue,
+            };
+            match column.as_str() {
+                // the sound level is averaged energetically via `sound_energy`
+                "samples" | "sound" => {},
+                // like in the other storages, the uptime of a bucket is the maximum
+                "uptime" => self.uptime = Some(self.uptime.map_or(value, |uptime| uptime.max(value))),
+                column => {
+                    let (sum, count) = self.fields.entry(column.to_string()).or_default();
+                    *sum += value * samples as f64;
+                    *count += samples;
+                },
+            }
+        }
+    }
+
+    /// Formats the averages as line protocol of the point replacing the points of the bucket.
+    fn line(&self, device: &str, timestamp: i64) -> String {
+        let mut fields: Vec<_> = self.fields.iter()
+            .map(|(key, &(sum, count))| (key.clone(), sum / count as f64))
+            .collect();
+        if let Some(&(sum, count)) = self.fields.get("sound_energy") {
+            fields.push(("sound".to_string(), 10. * (sum / count as f64).log10()));
+        }
+        fields.extend(self.uptime.map(|uptime| ("uptime".to_string(), uptime)));
+        fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut fields: Vec<_> = fields.into_iter()
+            .filter(|(_, value)| value.is_finite())
+            .map(|(key, value)| format!("{}={}", escape_key(&key), value))
+            .collect();
+        fields.push(format!("samples={}i", self.samples));
+        format!("measurements,device={},persistent=true {} {}", escape_key(device), fields.join(","), timestamp)
+    }
+}
+
+/// Escapes measurement names, tag keys and values and field keys in line protocol.
+fn escape_key(key: &str) -> String {
+    key.replace('\\', "\\\\").replace(',', "\\,").replace('=', "\\=").replace(' ', "\\ ")
+}
+
+/// Returns the aggregated fields to select and the prefix of their columns.
+fn aggregate_columns(aggregation: Aggregation, fields: Option<&[String]>) -> (String, &'static str) {
+    // the columns are named by the function, e.g. `mean_co2`
+    let (function, prefix) = match aggregation {
+        Aggregation::Avg => ("MEAN", "mean_"),
+        Aggregation::Min => ("MIN", "min_"),
+        Aggregation::Max => ("MAX", "max_"),
+        Aggregation::Median => ("MEDIAN", "median_"),
+        Aggregation::P95 => ("PERCENTILE", "percentile_"),
+        Aggregation::First => ("FIRST", "first_"),
+        Aggregation::Last => ("LAST", "last_"),
+        Aggregation::Count => ("COUNT", "count_"),
+    };
+    let call = |field: &str| match aggregation {
+        Aggregation::P95 => format!("PERCENTILE({}, 95)", field),
+        _ => format!("{}({})", function, field),
+    };
+    let sound = if aggregation == Aggregation::Avg { "sound_energy" } else { "sound" };
+    let columns = select_fields(fields, sound).into_iter()
+        .map(|field| if field == "uptime" && aggregation == Aggregation::Avg {
+            // like in the other storages, the uptime of a bucket is the maximum
+            format!("MAX(\"uptime\") AS {}", quote_identifier(&format!("{}uptime", prefix)))
+        } else if field.starts_with('/') {
+            // regular expressions name the columns by the function and field themselves
+            call(&field)
+        } else {
+            format!("{} AS {}", call(&quote_identifier(&field)), quote_identifier(&format!("{}{}", prefix, field)))
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    (columns, prefix)
+}
+
+/// Returns the fields to query with `sound` in place of the sound level, `rest.<name>` for selected fields
+/// unknown to the server or a regular expression matching all of them.
+fn select_fields(fields: Option<&[String]>, sound: &str) -> Vec<String> {
+    let mut selected: Vec<_> = FLOAT_FIELDS.iter().copied()
+        .chain(["sound", "uptime"])
+        .filter(|field| is_selected(fields, field))
+        .map(|field| if field == "sound" { sound.to_string() } else { field.to_string() })
+        .collect();
+    match fields {
+        None => selected.push("/^rest\\./".to_string()),
+        Some(fields) => selected.extend(fields.iter()
+            .filter(|field| needs_rest(Some(std::slice::from_ref(field))))
+            .map(|field| format!("rest.{}", field))),
+    }
+    selected
+}
+
+/// Quotes an identifier like a field key in InfluxQL.
+fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Quotes a string literal in InfluxQL.
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// Quotes a string field value in line protocol.
+fn quote_field(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Returns the time of the only point returned by a selector like `FIRST` or `LAST`.
+fn selected_time(series: &[Series]) -> Option<i64> {
+    series.first()?.values.first()?.first()?.as_i64()
+}
+
+/// Creates the measurements from the rows of a series, whose columns are the fields prefixed by `prefix`.
+/// With `average_sound`, the sound level is calculated from the averaged `sound_energy`.
+fn measurements(series: &Series, prefix: &str, average_sound: bool) -> Vec<Measurement> {
+    series.values.iter()
+        .map(|values| {
+            let mut fields: HashMap<&str, f64> = HashMap::new();
+            let mut rest = serde_json::Map::new();
+            for (column, value) in series.columns.iter().zip(values) {
+                let (column, value) = match (column.strip_prefix(prefix), value.as_f64()) {
+                    (Some(column), Some(value)) => (column, value),
+                    _ => continue,
+                };
+                match column.strip_prefix("rest.") {
+                    Some(key) => { rest.insert(key.to_string(), Value::from(value)); },
+                    None => { fields.insert(column, value); },
+                }
+            }
+            let field = |name: &str| fields.get(name).copied();
+            Measurement {
+                timestamp: values.first().and_then(Value::as_i64),
+                health: field("health"),
+                performance: field("performance"),
+                tvoc: field("tvoc"),
+                humidity: field("humidity"),
+                humidity_abs: field("humidity_abs"),
+                temperature: field("temperature"),
+                dewpt: field("dewpt"),
+                sound: if average_sound {
+                    field("sound_energy").map(|energy| 10. * energy.log10())
+                } else {
+                    field("sound")
+                },
+                pressure: field("pressure"),
+                no2: field("no2"),
+                co: field("co"),
+                co2: field("co2"),
+                pm1: field("pm1"),
+                pm2_5: field("pm2_5"),
+                pm10: field("pm10"),
+                oxygen: field("oxygen"),
+                o3: field("o3"),
+                so2: field("so2"),
+                uptime: field("uptime").map(|uptime| uptime as i64),
+                measuretime: field("measuretime"),
+                bat: field("bat"),
+                door_event: field("door_event"),
+                window_open: field("window_open"),
+                humidity_abs_delta: field("humidity_abs_delta"),
+                co2_delta: field("co2_delta"),
+                cnt0_3: field("cnt0_3"),
+                cnt0_5: field("cnt0_5"),
+                cnt1: field("cnt1"),
+                cnt2_5: field("cnt2_5"),
+                cnt5: field("cnt5"),
+                cnt10: field("cnt10"),
+                typ_ps: field("typ_ps"),
+                rest: Some(Value::Object(rest)),
+            }
+        })
+        .collect()
+}
+
+#[async_trait]
+impl MeasurementStorage for InfluxDb {
+    async fn device_ids(&self) -> Result<HashMap<String, String>, Error> {
+        let mut results = self.query("SELECT LAST(name) FROM devices GROUP BY id").await?;
+        Ok(results.pop().unwrap_or_default().into_iter()
+            .filter_map(|series| {
+                let id = series.tags.get("id")?.clone();
+                let name = series.values.first()?.get(1)?.as_str()?.to_string();
+                Some((name, id))
+            })
+            .collect())
+    }
+
+    async fn register_device(&self, id: &str, name: &str) -> Result<(), Error> {
+        self.write(&format!("devices,id={} name={} {}", escape_key(id), quote_field(name), Utc::now().timestamp_millis())).await
+    }
+
+    async fn timestamps(&self, device: &str) -> Result<Timestamps, Error> {
+        let results = self.query(&format!(
+            "SELECT FIRST(co2) FROM measurements WHERE device = {0}; SELECT LAST(co2) FROM measurements WHERE device = {0}",
+            quote(device),
+        )).await?;
+        Ok(Timestamps {
+            first: results.get(0).and_then(|series| selected_time(series)),
+            last: results.get(1).and_then(|series| selected_time(series)),
+        })
+    }
+
+    async fn data(&self, device: &str, first: u64, last: u64, _combine_datapoints: u64, combine_millis: u64) -> Result<Vec<Measurement>, Error> {
+        self.aggregated(device, first, last, combine_millis, Aggregation::Avg, None).await
+    }
+
+    async fn aggregate(&self, device: &str, first: u64, last: u64, _combine_datapoints: u64, combine_millis: u64, aggregations: &[Aggregation], fields: Option<&[String]>) -> Result<Vec<Vec<Measurement>>, Error> {
+        let mut series = Vec::new();
+        for &aggregation in aggregations {
+            series.push(self.aggregated(device, first, last, combine_millis, aggregation, fields).await?);
+        }
+        Ok(series)
+    }
+
+    async fn raw_rows(&self, device: &str, first: u64, last: u64, fields: Option<&[String]>, rows: mpsc::Sender<Measurement>) -> Result<(), Error> {
+        let columns: Vec<_> = select_fields(fields, "sound").iter()
+            .map(|field| if field.starts_with('/') { field.clone() } else { quote_identifier(field) })
+            .collect();
+        // points of both `persistent` series may have the same time, the next chunk continues
+        // at the time of the last point, skipping the points at that time which were already sent
+        let (mut from, mut offset) = (first, 0);
+        loop {
+            let mut results = self.query(&format!(
+                "SELECT {} FROM measurements WHERE device = {} AND time >= {}ms AND time <= {}ms LIMIT {} OFFSET {}",
+                columns.join(", "), quote(device), from, last, RAW_CHUNK, offset,
+            )).await?;
+            let series = match results.pop().and_then(|mut series| series.pop()) {
+                Some(series) => series,
+                None => return Ok(()),
+            };
+            let chunk = measurements(&series, "", false);
+            if let Some(timestamp) = chunk.last().and_then(|measurement| measurement.timestamp) {
+                let at_last = chunk.iter().rev().take_while(|measurement| measurement.timestamp == Some(timestamp)).count();
+                offset = if timestamp as u64 == from { offset + at_last } else { at_last };
+                from = timestamp as u64;
+            }
+            for measurement in chunk {
+                if rows.send(measurement).await.is_err() {
+                    return Ok(());
+                }
+            }
+            if series.values.len() < RAW_CHUNK {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn last_timestamps(&self, device: &str) -> Result<Option<(FilePath, u64)>, Error> {
+        let mut results = self.query(&format!(
+            "SELECT LAST(co2) FROM measurements WHERE device = {} AND persistent = 'true'",
+            quote(device),
+        )).await?;
+        Ok(selected_time(&results.pop().unwrap_or_default()).map(resume_from))
+    }
+
+    async fn store_entries(&self, device: &str, entries: &mut (dyn Stream<Item = (FilePath, Vec<Data14>)> + Unpin + Send), last_timestamp: Option<u64>) -> Result<u64, Error> {
+        let mut stored = 0;
+        while let Some((_file, entries)) = entries.next().await {
+            let entries: Vec<_> = entries.iter()
+                .filter(|entry| Some(entry.data11.timestamp) > last_timestamp)
+                .collect();
+            let timestamps: HashSet<_> = entries.iter().map(|entry| entry.data11.timestamp).collect();
+            let (first, last) = match (timestamps.iter().min(), timestamps.iter().max()) {
+                (Some(&first), Some(&last)) => (first, last),
+                _ => continue,
+            };
+
+            // the `persistent` tag puts a manual readout and the downloaded one of the same time into different
+            // series, the manual readout is deleted so that they aren't averaged together
+            let mut results = self.query(&format!(
+                "SELECT co2 FROM measurements WHERE device = {} AND persistent = 'false' AND time >= {}ms AND time <= {}ms",
+                quote(device), first, last,
+            )).await?;
+            let manual_readouts: Vec<_> = results.pop().unwrap_or_default().iter()
+                .flat_map(|series| &series.values)
+                .filter_map(|values| values.first()?.as_u64())
+                .filter(|timestamp| timestamps.contains(timestamp))
+                .map(|timestamp| format!(
+                    "DELETE FROM measurements WHERE device = {} AND persistent = 'false' AND time = {}ms",
+                    quote(device), timestamp,
+                ))
+                .collect();
+            if !manual_readouts.is_empty() {
+                self.query(&manual_readouts.join("; ")).await?;
+            }
+
+            let lines: Vec<_> = entries.iter().map(|entry| line(entry, true)).collect();
+            self.write(&lines.join("\n")).await?;
+            stored += lines.len() as u64;
+        }
+        Ok(stored)
+    }
+
+    async fn store_manual_readout(&self, data: &Data14) -> Result<(), Error> {
+        self.write(&line(data, false)).await
+    }
+
+    async fn set_file_state(&self, device: &str, files: &[FilePath], state: SyncState, error: Option<&str>) -> Result<(), Error> {
+        let mut lines = Vec::new();
+        for file in files {
+            // fields which aren't written keep their value, a pending file keeps its attempts and last error
+            let mut fields = vec![format!("path={}", quote_field(&file.path())), format!("state={}", quote_field(state.as_str()))];
+            match state {
+                SyncState::Pending => (),
+                SyncState::Succeeded => fields.push("error=\"\"".to_string()),
+                SyncState::Failed => {
+                    let mut results = self.query(&format!(
+                        "SELECT attempts FROM file_sync WHERE device = {} AND time = {}ms",
+                        quote(device), file.timestamp * 1000,
+                    )).await?;
+                    let attempts = results.pop().unwrap_or_default().first()
+                        .and_then(|series| series.values.first()?.get(1)?.as_i64())
+                        .unwrap_or_default();
+                    fields.push(format!("attempts={}i", attempts + 1));
+                    fields.push(format!("error={}", quote_field(error.unwrap_or_default())));
+                },
+            }
+            lines.push(format!("file_sync,device={} {} {}", escape_key(device), fields.join(","), file.timestamp * 1000));
+        }
+        if lines.is_empty() {
+            return Ok(());
+        }
+        self.write(&lines.join("\n")).await
+    }
+
+    async fn unsynced_files(&self, device: &str) -> Result<Vec<FileSync>, Error> {
+        let mut results = self.query(&format!(
+            "SELECT path, state, attempts, error FROM file_sync WHERE device = {} AND state != 'succeeded'",
+            quote(device),
+        )).await?;
+        let series = match results.pop().and_then(|mut series| series.pop()) {
+            Some(series) => series,
+            None => return Ok(Vec::new()),
+        };
+        Ok(series.values.iter()
+            .filter_map(|values| {
+                let mut path = values.get(1)?.as_str()?.split('/').map(str::parse::<u64>);
+                let file = FilePath {
+                    year: path.next()?.ok()? as u16,
+                    month: path.next()?.ok()? as u8,
+                    day: path.next()?.ok()? as u8,
+                    timestamp: path.next()?.ok()?,
+                };
+                Some(FileSync {
+                    file,
+                    state: SyncState::parse(values.get(2)?.as_str()?),
+                    attempts: values.get(3).and_then(Value::as_u64).unwrap_or_default() as u32,
+                    error: values.get(4).and_then(Value::as_str).filter(|error| !error.is_empty()).map(str::to_string),
+                })
+            })
+            .collect())
+    }
+
+    async fn apply_retention(&self, cutoffs: &RetentionCutoffs, dry_run: bool) -> Result<RetentionReport, Error> {
+        let mut report = RetentionReport::default();
+        let counts = |results: Vec<Vec<Series>>| -> Vec<(u64, u64)> {
+            results.into_iter().flatten()
+                .flat_map(|series| series.values)
+                .filter_map(|values| Some((values.first()?.as_u64()?, values.get(1)?.as_u64()?)))
+                .collect()
+        };
+
+        let manual_readouts = format!("FROM measurements WHERE persistent = 'false' AND time < {}ms", cutoffs.manual_readouts_before);
+        report.manual_readouts = counts(self.query(&format!("SELECT COUNT(co2) {}", manual_readouts)).await?).iter()
+            .map(|&(_, count)| count)
+            .sum();
+        if !dry_run {
+            self.query(&format!("DELETE {}", manual_readouts)).await?;
+        }
+
+        let before = match cutoffs.downsample_before {
+            Some(before) => before,
+            None => return Ok(report),
+        };
+        for device in self.device_ids().await?.into_values() {
+            let mut results = self.query(&format!(
+                "SELECT FIRST(co2) FROM measurements WHERE device = {} AND persistent = 'true' AND time < {}ms",
+                quote(&device), before,
+            )).await?;
+            let first = match selected_time(&results.pop().unwrap_or_default()) {
+                Some(first) => first,
+                None => continue,
+            };
+            // buckets consisting of a single point were already downsampled
+            let buckets: Vec<_> = counts(self.query(&format!(
+                "SELECT COUNT(co2) FROM measurements WHERE device = {} AND persistent = 'true' AND time >= {}ms AND time < {}ms GROUP BY time({}ms) fill(none)",
+                quote(&device), first, before, cutoffs.downsample_millis,
+            )).await?).into_iter().filter(|&(_, count)| count > 1).collect();
+            let first = match buckets.first() {
+                Some(&(first, _)) => first,
+                None => continue,
+            };
+            report.downsampled += buckets.iter().map(|&(_, count)| count).sum::<u64>();
+            report.buckets += buckets.len() as u64;
+            if dry_run {
+                continue;
+            }
+
+            // all points from the first bucket to downsample are replaced, already downsampled ones by themselves,
+            // manual readouts aren't part of the averages as they are kept or deleted above
+            // InfluxQL can't weight an aggregate by another field, the points are read in chunks and averaged here
+            let mut downsampled: BTreeMap<i64, Downsampled> = BTreeMap::new();
+            let mut from = first;
+            loop {
+                let mut results = self.query(&format!(
+                    "SELECT * FROM measurements WHERE device = {} AND persistent = 'true' AND time >= {}ms AND time < {}ms LIMIT {}",
+                    quote(&device), from, before, RAW_CHUNK,
+                )).await?;
+                let series = match results.pop().and_then(|mut series| series.pop()) {
+                    Some(series) => series,
+                    None => break,
+                };
+                for values in &series.values {
+                    if let Some(timestamp) = values.first().and_then(Value::as_i64) {
+                        let bucket = timestamp - timestamp.rem_euclid(cutoffs.downsample_millis as i64);
+                        downsampled.entry(bucket).or_default().add(&series.columns, values);
+                    }
+                }
+                // the times of the points of a series are unique
+                match series.values.last().and_then(|values| values.first()?.as_i64()) {
+                    Some(last) if series.values.len() == RAW_CHUNK => from = last as u64 + 1,
+                    _ => break,
+                }
+            }
+            let lines: Vec<_> = downsampled.iter().map(|(&bucket, downsampled)| downsampled.line(&device, bucket)).collect();
+            self.query(&format!(
+                "DELETE FROM measurements WHERE device = {} AND persistent = 'true' AND time >= {}ms AND time < {}ms",
+                quote(&device), first, before,
+            )).await?;
+            for lines in lines.chunks(5000) {
+                self.write(&lines.join("\n")).await?;
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream;
+    use serde_json::json;
+    use super::*;
+    use super::super::test_readout as readout;
+
+    #[test]
+    fn line_protocol() {
+        let line = line(&readout(1_600_000_000_000, 612., 3600), false);
+        assert!(line.starts_with("measurements,device=0123456789abcdef,persistent=false "));
+        assert!(line.ends_with(" 1600000000000"));
+        assert!(line.contains(",co2=612,"));
+        assert!(line.contains(",sound_energy=10000,"));
+        assert!(line.contains(",rest.radon=12"));
+        assert!(!line.contains("no2="));
+        assert_eq!(escape_key("a b,c=d"), "a\\ b\\,c\\=d");
+    }
+
+    #[test]
+    fn aggregate_columns_of_selected_fields() {
+        let fields = ["co2".to_string(), "sound".to_string(), "uptime".to_string(), "radon".to_string()];
+        let (columns, prefix) = aggregate_columns(Aggregation::Avg, Some(&fields));
+        assert_eq!(prefix, "mean_");
+        assert_eq!(columns, "MEAN(\"co2\") AS \"mean_co2\", MEAN(\"sound_energy\") AS \"mean_sound_energy\", \
+            MAX(\"uptime\") AS \"mean_uptime\", MEAN(\"rest.radon\") AS \"mean_rest.radon\"");
+        let (columns, _) = aggregate_columns(Aggregation::P95, Some(&fields[..2]));
+        assert_eq!(columns, "PERCENTILE(\"co2\", 95) AS \"percentile_co2\", PERCENTILE(\"sound\", 95) AS \"percentile_sound\"");
+        let (columns, _) = aggregate_columns(Aggregation::Max, None);
+        assert!(columns.ends_with(", MAX(/^rest\\./)"));
+        assert_eq!(quote_identifier("a\"b"), "\"a\\\"b\"");
+        assert_eq!(quote("it's"), "'it\\'s'");
+    }
+
+    #[test]
+    fn measurements_of_series() {
+        let series = Series {
+            tags: HashMap::new(),
+            columns: ["time", "mean_co2", "mean_sound_energy", "mean_uptime", "mean_rest.radon"].iter().map(|c| c.to_string()).collect(),
+            values: vec![vec![json!(1000), json!(612.0), json!(10000.0), json!(3600.0), json!(12.0)]],
+        };
+        let measurements = measurements(&series, "mean_", true);
+        assert_eq!(measurements.len(), 1);
+        assert_eq!(measurements[0].timestamp, Some(1000));
+        assert_eq!(measurements[0].co2, Some(612.));
+        assert_eq!(measurements[0].sound, Some(40.));
+        assert_eq!(measurements[0].uptime, Some(3600));
+        assert_eq!(measurements[0].rest, Some(json!({ "radon": 12.0 })));
+    }
+
+    #[test]
+    fn downsampled_points_are_weighted() {
+        let columns: Vec<_> = ["time", "co2", "device", "rest.radon", "samples", "sound", "sound_energy", "status", "uptime"].iter()
+            .map(|c| c.to_string())
+            .collect();
+        let mut downsampled = Downsampled::default();
+        downsampled.add(&columns, &[json!(1_600_000_000_000u64), json!(575.0), json!("a"), json!(12.0), json!(4), json!(40.0), json!(10000.0), Value::Null, json!(5.0)]);
+        downsampled.add(&columns, &[json!(1_600_000_130_000u64), json!(975.0), json!("a"), Value::Null, Value::Null, json!(50.0), json!(100000.0), json!("{}"), json!(6.0)]);
+        assert_eq!(downsampled.line("a b", 1_600_000_000_000), format!(
+            "measurements,device=a\\ b,persistent=true co2=655,rest.radon=12,sound={},sound_energy=28000,uptime=6,samples=5i 1600000000000",
+            10. * 28000f64.log10(),
+        ));
+    }
+
+    /// Connects to an InfluxDB 1.x at `INFLUXDB_TEST_URL` (default http://localhost:8086) and clears the test device,
+    /// the tests using it run with `cargo test -p server -- --ignored influxdb`
+    async fn test_influxdb(device: &str) -> InfluxDb {
+        let influxdb = InfluxDb::connect(&InfluxDbConfig {
+            url: std::env::var("INFLUXDB_TEST_URL").unwrap_or_else(|_| "http://localhost:8086".to_string()),
+            database: "airq_test".to_string(),
+            username: None,
+            password: None,
+        }).await;
+        influxdb.query(&format!("DROP SERIES FROM measurements WHERE device = {}", quote(device))).await.unwrap();
+        influxdb
+    }
+
+    #[rocket::async_test]
+    #[ignore]
+    async fn downloaded_readouts_replace_manual_readouts() {
+        let device = "0123456789abcdef";
+        let influxdb = test_influxdb(device).await;
+
+        let file = FilePath { year: 2020, month: 9, day: 13, timestamp: 1_600_000_000 };
+        influxdb.store_manual_readout(&readout(1_600_000_000_000, 700., 3500)).await.unwrap();
+        let entries = vec![(file, vec![readout(1_600_000_000_000, 600., 3600), readout(1_600_000_002_000, 620., 3602)])];
+        let stored = influxdb.store_entries(device, &mut stream::iter(entries), None).await.unwrap();
+        assert_eq!(stored, 2);
+
+        let averages = influxdb.data(device, 1_600_000_000_000, 1_600_000_010_000, 0, 10_000).await.unwrap();
+        assert_eq!(averages.len(), 1);
+        assert_eq!(averages[0].co2, Some(610.));
+        assert_eq!(averages[0].uptime, Some(3602));
+    }
+    #[rocket::async_test]
+    #[ignore]
+    async fn retention_dry_run_matches_applied() {
+        let device = "0123456789abcdef";
+        let influxdb = test_influxdb(device).await;
+        influxdb.register_device(device, "test").await.unwrap();
+        let file = FilePath { year: 2020, month: 9, day: 13, timestamp: 1_600_000_000 };
+        let entries = vec![(file, vec![
+            readout(1_600_000_020_000, 400., 1), readout(1_600_000_030_000, 500., 2), readout(1_600_000_040_000, 600., 3),
+        ])];
+        influxdb.store_entries(device, &mut stream::iter(entries), None).await.unwrap();
+        influxdb.store_manual_readout(&readout(1_600_000_050_000, 1000., 4)).await.unwrap();
+
+        // manual readouts are kept longer than downloaded measurements, they must not end up in the averages
+        let cutoffs = RetentionCutoffs {
+            manual_readouts_before: 1_600_000_000_000,
+            downsample_before: Some(1_600_000_080_000),
+            downsample_millis: 60_000,
+        };
+        let dry_run = influxdb.apply_retention(&cutoffs, true).await.unwrap();
+        let applied = influxdb.apply_retention(&cutoffs, false).await.unwrap();
+        for report in [&dry_run, &applied] {
+            assert_eq!((report.manual_readouts, report.downsampled, report.buckets), (0, 3, 1));
+        }
+        let again = influxdb.apply_retention(&cutoffs, true).await.unwrap();
+        assert_eq!((again.downsampled, again.buckets), (0, 0));
+
+        let mut results = influxdb.query(&format!(
+            "SELECT co2, uptime, samples FROM measurements WHERE device = {} AND persistent = 'true'",
+            quote(device),
+        )).await.unwrap();
+        let series = results.pop().unwrap().pop().unwrap();
+        let values: Vec<Vec<_>> = series.values.iter()
+            .map(|values| values.iter().map(|value| value.as_f64().unwrap()).collect())
+            .collect();
+        assert_eq!(values, vec![vec![1_600_000_020_000., 500., 3., 3.]]);
+    }
+}
@@ -0,0 +1,267 @@
+use std::collections::{BTreeMap, HashMap};
+use async_trait::async_trait;
+use chrono::{Datelike, Duration, TimeZone, Utc};
+use futures::Stream;
+use airq::{Data14, FilePath};
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::mpsc;
+use crate::{Timestamps, Measurement};
+use crate::fetch_data::Error;
+
+mod influxdb;
+mod postgres;
+mod sevendays;
+mod sqlite;
+
+pub use influxdb::InfluxDb;
+pub use postgres::Postgres;
+pub use sevendays::Sevendays;
+pub use sqlite::Sqlite;
+
+/// Download state of a file on the airQ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncState {
+    /// listed on the airQ but not stored yet
+    Pending,
+    Succeeded,
+    Failed,
+}
+
+impl SyncState {
+    fn as_str(self) -> &'static str {
+        match self {
+            SyncState::Pending => "pending",
+            SyncState::Succeeded => "succeeded",
+            SyncState::Failed => "failed",
+        }
+    }
+
+    fn parse(state: &str) -> SyncState {
+        match state {
+            "pending" => SyncState::Pending,
+            "succeeded" => SyncState::Succeeded,
+            _ => SyncState::Failed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileSync {
+    pub file: FilePath,
+    pub state: SyncState,
+    /// number of failed downloads
+    pub attempts: u32,
+    /// error of the last failed download
+    pub error: Option<String>,
+}
+
+/// How the measurements in a bucket are combined into one value per field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregation {
+    Avg,
+    Min,
+    Max,
+    Median,
+    /// 95th percentile
+    P95,
+    /// first value of the bucket
+    First,
+    /// last value of the bucket
+    Last,
+    /// number of values in the bucket
+    Count,
+}
+
+impl Aggregation {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Aggregation::Avg => "avg",
+            Aggregation::Min => "min",
+            Aggregation::Max => "max",
+            Aggregation::Median => "median",
+            Aggregation::P95 => "p95",
+            Aggregation::First => "first",
+            Aggregation::Last => "last",
+            Aggregation::Count => "count",
+        }
+    }
+
+    /// Parses a comma separated list of aggregations like `min,avg,max`.
+    pub fn parse_list(list: &str) -> Result<Vec<Aggregation>, String> {
+        list.split(',')
+            .map(|name| match name.trim() {
+                "avg" => Ok(Aggregation::Avg),
+                "min" => Ok(Aggregation::Min),
+                "max" => Ok(Aggregation::Max),
+                "median" => Ok(Aggregation::Median),
+                "p95" => Ok(Aggregation::P95),
+                "first" => Ok(Aggregation::First),
+                "last" => Ok(Aggregation::Last),
+                "count" => Ok(Aggregation::Count),
+                name => Err(format!("unknown aggregation {:?}", name)),
+            })
+            .collect()
+    }
+
+    /// Aggregates values given in chronological order, `None` if there are none.
+    /// Percentiles are interpolated linearly like `percentile_cont` in SQL.
+    fn apply(self, values: &[f64]) -> Option<f64> {
+        if values.is_empty() {
+            return None;
+        }
+        let percentile = |fraction: f64| {
+            let mut sorted = values.to_vec();
+            sorted.sort_by(f64::total_cmp);
+            let rank = fraction * (sorted.len() - 1) as f64;
+            let lower = sorted[rank.floor() as usize];
+            let upper = sorted[rank.ceil() as usize];
+            lower + (upper - lower) * rank.fract()
+        };
+        Some(match self {
+            Aggregation::Avg => values.iter().sum::<f64>() / values.len() as f64,
+            Aggregation::Min => values.iter().copied().fold(f64::INFINITY, f64::min),
+            Aggregation::Max => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            Aggregation::Median => percentile(0.5),
+            Aggregation::P95 => percentile(0.95),
+            Aggregation::First => values[0],
+            Aggregation::Last => values[values.len() - 1],
+            Aggregation::Count => values.len() as f64,
+        })
+    }
+}
+
+/// numeric fields of `Measurement` besides `sound` and `uptime`, which are float columns in the SQL storages
+const FLOAT_FIELDS: [&str; 30] = [
+    "health", "performance", "tvoc", "humidity", "humidity_abs", "temperature", "dewpt", "pressure", "no2", "co",
+    "co2", "pm1", "pm2_5", "pm10", "oxygen", "o3", "so2", "measuretime", "bat", "door_event",
+    "window_open", "humidity_abs_delta", "co2_delta", "cnt0_3", "cnt0_5", "cnt1", "cnt2_5", "cnt5", "cnt10", "typ_ps",
+];
+
+/// Retention rules as timestamps in ms.
+#[derive(Debug, Clone, Copy)]
+pub struct RetentionCutoffs {
+    /// manual readouts before are deleted
+    pub manual_readouts_before: u64,
+    /// downloaded measurements before are replaced by their averages over `downsample_millis`
+    pub downsample_before: Option<u64>,
+    pub downsample_millis: u64,
+}
+
+/// Rows deleted by the retention rules, or which would be deleted in a dry run.
+#[derive(Debug, Default, Serialize)]
+pub struct RetentionReport {
+    pub manual_readouts: u64,
+    /// downloaded measurements replaced by averages
+    pub downsampled: u64,
+    /// averages replacing them
+    pub buckets: u64,
+}
+
+#[async_trait]
+pub trait MeasurementStorage: Send + Sync {
+    // async fn last_file(&self) -> Option<FilePath>;
+    // async fn last_timestamp(&self) -> u64;
+    /// Returns the ids of all known devices by their name.
+    async fn device_ids(&self) -> Result<HashMap<String, String>, Error>;
+    async fn register_device(&self, id: &str, name: &str) -> Result<(), Error>;
+    async fn timestamps(&self, device: &str) -> Result<Timestamps, Error>;
+    async fn data(&self, device: &str, first: u64, last: u64, combine_datapoints: u64, combine_millis: u64, ) -> Result<Vec<Measurement>, Error>;
+    /// Combines the measurements in the same buckets as `data` once for each of the aggregations.
+    /// The buckets of all returned series are the same, so they can be zipped.
+    ///
+    /// Only `fields` are needed if given, storages may leave the other fields empty.
+    async fn aggregate(&self, device: &str, first: u64, last: u64, combine_datapoints: u64, combine_millis: u64, aggregations: &[Aggregation], _fields: Option<&[String]>) -> Result<Vec<Vec<Measurement>>, Error> {
+        let mut series = Vec::new();
+        for &aggregation in aggregations {
+            if aggregation != Aggregation::Avg {
+                return Err(Error::UnsupportedAggregation(aggregation.as_str()));
+            }
+            series.push(self.data(device, first, last, combine_datapoints, combine_millis).await?);
+        }
+        Ok(series)
+    }
+    /// Sends every stored measurement of the device in the range in chronological order without combining any,
+    /// including manual readouts, until all are sent or `rows` is closed.
+    ///
+    /// Only `fields` are needed if given, storages may leave the other fields empty.
+    async fn raw_rows(&self, device: &str, first: u64, last: u64, fields: Option<&[String]>, rows: mpsc::Sender<Measurement>) -> Result<(), Error>;
+    async fn last_timestamps(&self, device: &str) -> Result<Option<(FilePath, u64)>, Error>;
+    /// Stores the entries newer than `last_timestamp` and returns the number of stored rows.
+    async fn store_entries(&self, device: &str, entries: &mut (dyn Stream<Item = (FilePath, Vec<Data14>)> + Unpin + Send), last_timestamp: Option<u64>) -> Result<u64, Error>;
+    async fn store_manual_readout(&self, data: &Data14) -> Result<(), Error>;
+    /// Deletes the manual readouts which weren't replaced by downloaded measurements
+    /// and downsamples the downloaded measurements according to the cutoffs.
+    /// In a dry run only reports which rows would be deleted.
+    async fn apply_retention(&self, cutoffs: &RetentionCutoffs, dry_run: bool) -> Result<RetentionReport, Error>;
+    /// Records the download state of the given files, counting an attempt for each failure.
+    async fn set_file_state(&self, device: &str, files: &[FilePath], state: SyncState, error: Option<&str>) -> Result<(), Error>;
+    /// Returns the files of the device which weren't downloaded and stored successfully yet.
+    async fn unsynced_files(&self, device: &str) -> Result<Vec<FileSync>, Error>;
+    /// Returns the number of open and idle database connections, if the storage uses a connection pool.
+    fn pool_stats(&self) -> Option<(u32, usize)> { None }
+}
+
+/// Returns whether the field is one of `fields`, all fields are selected if not given.
+pub fn is_selected(fields: Option<&[String]>, field: &str) -> bool {
+    fields.map_or(true, |fields| fields.iter().any(|selected| selected == field))
+}
+
+/// Returns whether any of `fields` is unknown to the server by name and thus stored in `rest`.
+fn needs_rest(fields: Option<&[String]>) -> bool {
+    fields.map_or(true, |fields| fields.iter().any(|field| {
+        !FLOAT_FIELDS.contains(&field.as_str()) && field != "sound" && field != "uptime"
+    }))
+}
+
+/// Aggregates the numeric fields of `rest`, i.e. the fields the airQ sent which the server doesn't know by name.
+/// Of values sent together with their uncertainty as `[value, uncertainty]`, only the value is aggregated.
+/// The rests are expected in chronological order.
+fn aggregate_rest<'a>(rests: impl Iterator<Item = &'a HashMap<String, Value>>, aggregation: Aggregation) -> Value {
+    let mut values: BTreeMap<&str, Vec<f64>> = BTreeMap::new();
+    for rest in rests {
+        for (key, value) in rest {
+            let value = match value {
+                Value::Array(values) => values.first().and_then(Value::as_f64),
+                value => value.as_f64(),
+            };
+            if let Some(value) = value {
+                values.entry(key).or_default().push(value);
+            }
+        }
+    }
+    values.into_iter()
+        .filter_map(|(key, values)| Some((key.to_string(), Value::from(aggregation.apply(&values)?))))
+        .collect()
+}
+
+/// Returns from where to continue downloading the files of the airQ after the last stored measurement
+/// for storages which don't track the downloaded files.
+///
+/// Continues with all files of the day before the last measurement,
+/// because the file containing the last measurement may have been started on that day.
+fn resume_from(last_timestamp: i64) -> (FilePath, u64) {
+    let day_before = Utc.timestamp_millis(last_timestamp) - Duration::days(1);
+    (FilePath {
+        year: day_before.year() as u16,
+        month: day_before.month() as u8,
+        day: day_before.day() as u8,
+        timestamp: 0,
+    }, last_timestamp as u64)
+}
+
+/// Returns a readout of the device `0123456789abcdef` with an additional field `radon` unknown to the server.
+#[cfg(test)]
+fn test_readout(timestamp: u64, co2: f64, uptime: u64) -> Data14 {
+    serde_json::from_value(serde_json::json!({
+        "DeviceID": "0123456789abcdef", "Status": "OK", "uptime": uptime, "health": 900.0, "performance": 800.0,
+        "measuretime": 2000.0, "timestamp": timestamp, "bat": null, "door_event": null, "window_open": null,
+        "tvoc": [100.0, 10.0], "humidity": [45.0, 3.0], "humidity_abs": [9.0, 1.0], "dHdt": null,
+        "temperature": [21.5, 0.5], "dewpt": [9.3, 1.0], "sound": [40.0, 3.0], "pressure": [1013.0, 1.0],
+        "no2": null, "co": null, "co2": [co2, 30.0], "dCO2dt": null, "pm1": [1.0, 1.0], "pm2_5": [2.0, 1.0],
+        "pm10": [3.0, 1.0], "cnt0_3": [100.0, 10.0], "cnt0_5": [50.0, 10.0], "cnt1": [10.0, 5.0], "cnt2_5": [1.0, 1.0],
+        "cnt5": [0.0, 1.0], "cnt10": [0.0, 1.0], "TypPS": 0.5, "oxygen": [20.9, 1.0], "o3": null, "so2": null,
+        "radon": [12.0, 3.0],
+    })).unwrap()
+}
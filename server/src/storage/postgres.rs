@@ -0,0 +1,835 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use sqlx::{PgPool, Row};
+use sqlx::postgres::{PgPoolOptions, PgRow};
+use async_trait::async_trait;
+use futures::{Stream, StreamExt};
+use airq::{Data11, Data14, FilePath};
+use serde_json::Value;
+use tokio::sync::mpsc;
+use crate::{Timestamps, Measurement};
+use crate::fetch_data::Error;
+use super::{Aggregation, FLOAT_FIELDS, FileSync, MeasurementStorage, RetentionCutoffs, RetentionReport, SyncState, is_selected, needs_rest, resume_from};
+
+/// the continuous aggregate is used for buckets of at least an hour
+const HOUR_MILLIS: u64 = 60 * 60 * 1000;
+/// bucket sizes of the `rollups` in seconds, coarsest first
+const ROLLUP_RESOLUTIONS: [i32; 2] = [24 * 60 * 60, 60 * 60];
+/// aggregations which can be calculated from the `rollups`
+const ROLLUP_AGGREGATIONS: [Aggregation; 4] = [Aggregation::Avg, Aggregation::Min, Aggregation::Max, Aggregation::Count];
+/// rows per query of `raw_rows`, the connection is released between the queries
+const RAW_CHUNK: i64 = 10_000;
+
+pub struct Postgres {
+    pool: PgPool,
+    /// `measurements` is a TimescaleDB hypertable with the continuous aggregate `measurements_hourly`
+    timescale: bool,
+    /// name of the device the measurements from before multiple devices were supported are assigned to
+    legacy_device: Option<String>,
+    /// the measurements stored before the `rollups` existed are rolled up, until then they aren't read
+    rollups_ready: Arc<AtomicBool>,
+}
+
+impl Postgres {
+    pub async fn connect(url: &str, timescale: bool, legacy_device: Option<String>) -> Postgres {
+        let pool = PgPoolOptions::new()
+            .max_connections(8)
+            .connect(url).await
+            .unwrap();
+        Postgres { pool, timescale, legacy_device, rollups_ready: Arc::new(AtomicBool::new(false)) }
+    }
+
+    /// Applies all migrations from `server/migrations` which haven't been applied yet
+    /// and sets up TimescaleDB if enabled.
+    pub async fn migrate(&self) -> Result<(), Error> {
+        sqlx::migrate!().run(&self.pool).await?;
+        if self.timescale {
+            self.setup_timescale().await?;
+        }
+        Ok(())
+    }
+
+    /// Rolls up the measurements stored before the `rollups` existed in the background, a day per transaction.
+    ///
+    /// Each day replaces its buckets, which `store_entries` may have added to in the meantime.
+    pub fn backfill_rollups(&self) {
+        let pool = self.pool.clone();
+        let ready = Arc::clone(&self.rollups_ready);
+        tokio::spawn(async move {
+            let result: Result<(), Error> = async {
+                loop {
+                    let mut tx = pool.begin().await?;
+                    let pending = sqlx::query("SELECT 1 FROM rollup_backfill WHERE next <= until FOR UPDATE;")
+                        .fetch_optional(&mut tx).await?;
+                    if pending.is_none() {
+                        sqlx::query("DELETE FROM rollup_backfill;").execute(&mut tx).await?;
+                        tx.commit().await?;
+                        return Ok(());
+                    }
+                    // blocks `store_entries` until the day is replaced, its rows are either part of it or added afterwards
+                    sqlx::query("LOCK TABLE rollups IN EXCLUSIVE MODE;").execute(&mut tx).await?;
+                    sqlx::query("DELETE FROM rollups USING rollup_backfill WHERE bucket >= next AND bucket < next + interval '1 day';")
+                        .execute(&mut tx).await?;
+                    // downsampled rows count as the measurements they replaced
+                    sqlx::query(r#"
+                        INSERT INTO rollups (device, resolution, bucket, field, min, max, sum, count)
+                        SELECT
+                            device, resolution, date_bin(make_interval(secs => resolution), timestamp, 'epoch') as bucket, field,
+                            min(value), max(value), sum(CASE field WHEN 'sound' THEN power(10, value / 10) ELSE value END * samples), sum(samples)
+                        FROM rollup_backfill, measurements, measurement_fields(to_jsonb(measurements) - 'samples'), unnest(ARRAY[3600, 86400]) AS resolution
+                        WHERE persistent AND timestamp >= next AND timestamp < next + interval '1 day'
+                        GROUP BY device, resolution, bucket, field;
+                    "#).execute(&mut tx).await?;
+                    sqlx::query("UPDATE rollup_backfill SET next = next + interval '1 day';").execute(&mut tx).await?;
+                    tx.commit().await?;
+                }
+            }.await;
+            match result {
+                Ok(()) => ready.store(true, Ordering::Relaxed),
+                Err(e) => eprintln!("rolling up the stored measurements failed, long ranges are read from the measurements: {:?}", e),
+            }
+        });
+    }
+
+    /// Turns `measurements` into a hypertable and creates the hourly continuous aggregate.
+    ///
+    /// The statements are idempotent and not part of the migrations, because TimescaleDB is optional.
+    /// The aggregate doesn't contain the fields in `rest`.
+    async fn setup_timescale(&self) -> Result<(), Error> {
+        sqlx::query("CREATE EXTENSION IF NOT EXISTS timescaledb;").execute(&self.pool).await?;
+        sqlx::query("SELECT create_hypertable('measurements', 'timestamp', if_not_exists => true, migrate_data => true);")
+            .execute(&self.pool).await?;
+        // sums and counts of each field, which unlike averages can be combined into larger buckets,
+        // downsampled rows count as the measurements they replaced
+        let totals: Vec<_> = FLOAT_FIELDS.iter()
+            .map(|field| (field.to_string(), field.to_string()))
+            .chain([("power(10, sound / 10)".to_string(), "sound".to_string())])
+            .map(|(value, field)| format!(
+                "sum({value} * samples) as {field}_sum, sum(CASE WHEN {field} IS NOT NULL THEN samples END) as {field}_count",
+                value = value, field = field,
+            ))
+            .collect();
+        sqlx::query(&format!(
+            r#"
+                CREATE MATERIALIZED VIEW IF NOT EXISTS measurements_hourly
+                WITH (timescaledb.continuous) AS
+                SELECT device, time_bucket(INTERVAL '1 hour', timestamp) as bucket, min(timestamp) as timestamp, {}, max(uptime) as uptime
+                FROM measurements
+                GROUP BY device, bucket
+                WITH NO DATA;
+            "#,
+            totals.join(", "),
+        )).execute(&self.pool).await?;
+        // refresh the whole range, only buckets invalidated by inserts are recomputed
+        sqlx::query(r#"
+            SELECT add_continuous_aggregate_policy('measurements_hourly',
+                start_offset => NULL, end_offset => INTERVAL '1 hour', schedule_interval => INTERVAL '1 hour',
+                if_not_exists => true);
+        "#).execute(&self.pool).await?;
+        sqlx::query("CALL refresh_continuous_aggregate('measurements_hourly', NULL, now() - INTERVAL '1 hour');")
+            .execute(&self.pool).await?;
+        Ok(())
+    }
+
+    /// Averages the measurements in buckets of `combine_millis` with `time_bucket`, aligned to the epoch like `date_bin`,
+    /// from the sums and counts of the hourly continuous aggregate, which doesn't contain `rest`.
+    /// The buckets are rounded up to whole hours, so that an hour lies within a single bucket.
+    ///
+    /// Not checked at compile time, because TimescaleDB is optional.
+    async fn timescale_data(&self, device: &str, first: u64, last: u64, combine_millis: u64) -> Result<Vec<Measurement>, Error> {
+        let averages: Vec<_> = FLOAT_FIELDS.iter()
+            .map(|field| format!("sum({0}_sum) / sum({0}_count) as {0}", field))
+            .collect();
+        let query = format!(
+            r#"
+                SELECT
+                    extract(epoch from min(timestamp))::int8 * 1000 as timestamp, {},
+                    10 * log10(sum(sound_sum) / sum(sound_count)) as sound, max(uptime) as uptime, NULL::jsonb as rest
+                FROM measurements_hourly
+                WHERE device = $1 AND bucket >= to_timestamp($2::float8 / 1000) AND bucket <= to_timestamp($3::float8 / 1000)
+                GROUP BY time_bucket(make_interval(secs => $4::float8 / 1000), bucket, origin => 'epoch'::timestamptz)
+                ORDER BY timestamp;
+            "#,
+            averages.join(", "),
+        );
+        let combine_millis = (combine_millis + HOUR_MILLIS - 1) / HOUR_MILLIS * HOUR_MILLIS;
+        let rows = sqlx::query(&query)
+            .bind(device)
+            .bind(first as f64)
+            .bind(last as f64)
+            .bind(combine_millis as f64)
+            .fetch_all(&self.pool).await?;
+        rows.iter().map(measurement).collect()
+    }
+
+    /// Returns the resolution of the coarsest rollups for buckets of `combine_millis`, if they are complete.
+    fn rollup_resolution(&self, combine_millis: u64) -> Option<i32> {
+        ROLLUP_RESOLUTIONS.iter().copied()
+            .find(|&resolution| combine_millis >= resolution as u64 * 1000)
+            .filter(|_| self.rollups_ready.load(Ordering::Relaxed))
+    }
+
+    /// Aggregates the measurements in buckets of `combine_millis` from the rollups of the given resolution,
+    /// rounded up to a multiple of the resolution.
+    ///
+    /// The rollups don't contain manual readouts, the buckets from the one of the last downloaded measurement on
+    /// are aggregated from `measurements` to include the manual readouts taken since.
+    #[allow(clippy::too_many_arguments)]
+    async fn rollup_data(&self, device: &str, first: u64, last: u64, resolution: i32, combine_millis: u64, aggregation: Aggregation, fields: Option<&[String]>) -> Result<Vec<Measurement>, Error> {
+        let downloaded = sqlx::query!(
+            r#"SELECT extract(epoch from max(timestamp))::int8 * 1000 as timestamp FROM measurements WHERE device = $1 AND persistent;"#,
+            device,
+        ).fetch_one(&self.pool).await?;
+        // a rollup only lies within a single bucket if the buckets are a multiple of its resolution
+        let resolution_millis = resolution as u64 * 1000;
+        let combine_millis = (combine_millis + resolution_millis - 1) / resolution_millis * resolution_millis;
+        // the rollups are read for the buckets before the one containing the rollup of the last downloaded measurement,
+        // the measurements from the end of the last rollup read on are aggregated from `measurements`
+        let (rollups_end, raw_first) = match downloaded.timestamp {
+            Some(timestamp) => {
+                let rollup = timestamp as u64 / resolution_millis * resolution_millis;
+                let bucket = rollup / combine_millis * combine_millis;
+                (bucket, (bucket + resolution_millis - 1) / resolution_millis * resolution_millis)
+            },
+            None => (0, 0),
+        };
+
+        let mut measurements = if first < rollups_end {
+            self.rollup_buckets(device, first, last.min(rollups_end - 1), resolution, combine_millis, aggregation, fields).await?
+        } else {
+            Vec::new()
+        };
+        if raw_first.max(first) <= last {
+            measurements.extend(self.raw_data(device, raw_first.max(first), last, combine_millis, aggregation, fields).await?);
+        }
+        Ok(measurements)
+    }
+
+    /// Aggregates the rollups of the given resolution in buckets of `combine_millis`.
+    #[allow(clippy::too_many_arguments)]
+    async fn rollup_buckets(&self, device: &str, first: u64, last: u64, resolution: i32, combine_millis: u64, aggregation: Aggregation, fields: Option<&[String]>) -> Result<Vec<Measurement>, Error> {
+        let rows = sqlx::query!(
+            r#"
+                SELECT
+                    extract(epoch from date_bin(make_interval(secs => $5::float8 / 1000), bucket, 'epoch'))::int8 as "bin!",
+                    extract(epoch from min(bucket))::int8 * 1000 as "timestamp!",
+                    field, sum(sum) / sum(count)::float8 as "avg!", min(min) as "min!", max(max) as "max!",
+                    sum(count)::float8 as "count!"
+                FROM rollups
+                WHERE device = $1 AND resolution = $2 AND bucket >= to_timestamp($3::float8 / 1000) AND bucket <= to_timestamp($4::float8 / 1000)
+                    AND ($6::text[] IS NULL OR field = ANY($6) OR field LIKE 'rest.%' AND substr(field, 6) = ANY($6))
+                GROUP BY 1, field
+                ORDER BY 1;
+            "#,
+            device, resolution, first as f64, last as f64, combine_millis as f64, fields.map(<[String]>::to_vec),
+        ).fetch_all(&self.pool).await?;
+
+        // one row per bucket and field
+        let mut measurements = Vec::new();
+        let mut bucket: Option<(i64, i64, HashMap<String, (f64, f64)>)> = None;
+        for row in rows {
+            let value = match aggregation {
+                Aggregation::Min => row.min,
+                Aggregation::Max => row.max,
+                Aggregation::Count => row.count,
+                _ => row.avg,
+            };
+            match &mut bucket {
+                Some((bin, timestamp, fields)) if *bin == row.bin => {
+                    *timestamp = (*timestamp).min(row.timestamp);
+                    fields.insert(row.field, (value, row.max));
+                },
+                _ => {
+                    if let Some((_, timestamp, fields)) = bucket.take() {
+                        measurements.push(rollup_measurement(timestamp, &fields, aggregation));
+                    }
+                    bucket = Some((row.bin, row.timestamp, HashMap::from([(row.field, (value, row.max))])));
+                },
+            }
+        }
+        if let Some((_, timestamp, fields)) = bucket {
+            measurements.push(rollup_measurement(timestamp, &fields, aggregation));
+        }
+        Ok(measurements)
+    }
+
+    /// Aggregates the measurements in buckets of `combine_millis` with any aggregation, only `fields` if given.
+    async fn raw_data(&self, device: &str, first: u64, last: u64, combine_millis: u64, aggregation: Aggregation, fields: Option<&[String]>) -> Result<Vec<Measurement>, Error> {
+        // fields which aren't needed are selected as NULL
+        let columns: Vec<_> = FLOAT_FIELDS.iter()
+            .map(|column| if is_selected(fields, column) {
+                format!("{} as {}", aggregate_sql(aggregation, column), column)
+            } else {
+                format!("NULL::float8 as {}", column)
+            })
+            .collect();
+        let (sound, uptime) = match aggregation {
+            Aggregation::Avg => (format!("10 * log10({})", weighted_avg_sql("power(10, sound / 10)", "sound")), "max(uptime)".to_string()),
+            _ => (aggregate_sql(aggregation, "sound"), format!("({})::int8", aggregate_sql(aggregation, "uptime"))),
+        };
+        let sound = if is_selected(fields, "sound") { sound } else { "NULL::float8".to_string() };
+        let uptime = if is_selected(fields, "uptime") { uptime } else { "NULL::int8".to_string() };
+        let query = format!(
+            r#"
+                WITH selected AS (
+                    SELECT *, date_bin(make_interval(secs => $4::float8 / 1000), timestamp, 'epoch') as bucket
+                    FROM measurements
+                    WHERE device = $1 AND timestamp >= to_timestamp($2::float8 / 1000) AND timestamp <= to_timestamp($3::float8 / 1000)
+                ), rest AS (
+                    SELECT bucket, jsonb_object_agg(key, value) as rest
+                    FROM (
+                        SELECT bucket, key, {rest} as value
+                        FROM (
+                            SELECT bucket, timestamp, samples, key, CASE jsonb_typeof(value) WHEN 'array' THEN (value ->> 0)::float8 ELSE (value #>> '{{}}')::float8 END as value
+                            FROM selected, jsonb_each(selected.rest)
+                            WHERE (jsonb_typeof(value) = 'number' OR jsonb_typeof(value -> 0) = 'number')
+                                AND ($5::text[] IS NULL OR key = ANY($5))
+                        ) rest_values
+                        GROUP BY bucket, key
+                    ) aggregates
+                    GROUP BY bucket
+                )
+                SELECT
+                    extract(epoch from min(timestamp))::int8 * 1000 as timestamp, {columns},
+                    {sound} as sound, {uptime} as uptime, rest.rest as rest
+                FROM selected LEFT JOIN rest USING (bucket)
+                GROUP BY bucket, rest.rest
+                ORDER BY timestamp;
+            "#,
+            rest = aggregate_sql(aggregation, "value"),
+            columns = columns.join(", "),
+            sound = sound,
+            uptime = uptime,
+        );
+        let rows = sqlx::query(&query)
+            .bind(device)
+            .bind(first as f64)
+            .bind(last as f64)
+            .bind(combine_millis as f64)
+            .bind(fields.map(<[String]>::to_vec))
+            .fetch_all(&self.pool).await?;
+        rows.iter().map(measurement).collect()
+    }
+}
+
+/// Returns the SQL aggregating the column of the rows in a bucket, ignoring NULLs.
+///
+/// Averages and counts are weighted by the number of readouts a downsampled row averages.
+fn aggregate_sql(aggregation: Aggregation, column: &str) -> String {
+    match aggregation {
+        Aggregation::Avg => weighted_avg_sql(column, column),
+        Aggregation::Min => format!("min({})", column),
+        Aggregation::Max => format!("max({})", column),
+        Aggregation::Median => format!("percentile_cont(0.5) WITHIN GROUP (ORDER BY {})", column),
+        Aggregation::P95 => format!("percentile_cont(0.95) WITHIN GROUP (ORDER BY {})", column),
+        Aggregation::First => format!("(array_agg({0} ORDER BY timestamp) FILTER (WHERE {0} IS NOT NULL))[1]", column),
+        Aggregation::Last => format!("(array_agg({0} ORDER BY timestamp DESC) FILTER (WHERE {0} IS NOT NULL))[1]", column),
+        Aggregation::Count => format!("coalesce(sum(samples) FILTER (WHERE {} IS NOT NULL), 0)::float8", column),
+    }
+}
+
+/// Returns the SQL averaging `value` over the rows of a bucket in which `column` isn't NULL,
+/// weighted by the number of readouts a downsampled row averages.
+fn weighted_avg_sql(value: &str, column: &str) -> String {
+    format!("sum({} * samples) / sum(samples) FILTER (WHERE {} IS NOT NULL)", value, column)
+}
+
+/// Creates a measurement from the aggregated values and maxima of the fields in a bucket of the rollups.
+fn rollup_measurement(timestamp: i64, fields: &HashMap<String, (f64, f64)>, aggregation: Aggregation) -> Measurement {
+    let avg = |name: &str| fields.get(name).map(|&(avg, _)| avg);
+    let rest = fields.iter()
+        .filter_map(|(field, &(avg, _))| Some((field.strip_prefix("rest.")?.to_string(), Value::from(avg))))
+        .collect();
+    Measurement {
+        timestamp: Some(timestamp),
+        health: avg("health"),
+        performance: avg("performance"),
+        tvoc: avg("tvoc"),
+        humidity: avg("humidity"),
+        humidity_abs: avg("humidity_abs"),
+        temperature: avg("temperature"),
+        dewpt: avg("dewpt"),
+        // the average of `sound` is of the sound energy
+        sound: match aggregation {
+            Aggregation::Avg => avg("sound").map(|energy| 10. * energy.log10()),
+            _ => avg("sound"),
+        },
+        pressure: avg("pressure"),
+        no2: avg("no2"),
+        co: avg("co"),
+        co2: avg("co2"),
+        pm1: avg("pm1"),
+        pm2_5: avg("pm2_5"),
+        pm10: avg("pm10"),
+        oxygen: avg("oxygen"),
+        o3: avg("o3"),
+        so2: avg("so2"),
+        uptime: match aggregation {
+            Aggregation::Avg => fields.get("uptime").map(|&(_, max)| max as i64),
+            _ => avg("uptime").map(|uptime| uptime as i64),
+        },
+        measuretime: avg("measuretime"),
+        bat: avg("bat"),
+        door_event: avg("door_event"),
+        window_open: avg("window_open"),
+        humidity_abs_delta: avg("humidity_abs_delta"),
+        co2_delta: avg("co2_delta"),
+        cnt0_3: avg("cnt0_3"),
+        cnt0_5: avg("cnt0_5"),
+        cnt1: avg("cnt1"),
+        cnt2_5: avg("cnt2_5"),
+        cnt5: avg("cnt5"),
+        cnt10: avg("cnt10"),
+        typ_ps: avg("typ_ps"),
+        rest: Some(Value::Object(rest)),
+    }
+}
+
+fn measurement(row: &PgRow) -> Result<Measurement, Error> {
+    Ok(Measurement {
+        timestamp: row.try_get("timestamp")?,
+        health: row.try_get("health")?,
+        performance: row.try_get("performance")?,
+        tvoc: row.try_get("tvoc")?,
+        humidity: row.try_get("humidity")?,
+        humidity_abs: row.try_get("humidity_abs")?,
+        temperature: row.try_get("temperature")?,
+        dewpt: row.try_get("dewpt")?,
+        sound: row.try_get("sound")?,
+        pressure: row.try_get("pressure")?,
+        no2: row.try_get("no2")?,
+        co: row.try_get("co")?,
+        co2: row.try_get("co2")?,
+        pm1: row.try_get("pm1")?,
+        pm2_5: row.try_get("pm2_5")?,
+        pm10: row.try_get("pm10")?,
+        oxygen: row.try_get("oxygen")?,
+        o3: row.try_get("o3")?,
+        so2: row.try_get("so2")?,
+        uptime: row.try_get("uptime")?,
+        measuretime: row.try_get("measuretime")?,
+        bat: row.try_get("bat")?,
+        door_event: row.try_get("door_event")?,
+        window_open: row.try_get("window_open")?,
+        humidity_abs_delta: row.try_get("humidity_abs_delta")?,
+        co2_delta: row.try_get("co2_delta")?,
+        cnt0_3: row.try_get("cnt0_3")?,
+        cnt0_5: row.try_get("cnt0_5")?,
+        cnt1: row.try_get("cnt1")?,
+        cnt2_5: row.try_get("cnt2_5")?,
+        cnt5: row.try_get("cnt5")?,
+        cnt10: row.try_get("cnt10")?,
+        typ_ps: row.try_get("typ_ps")?,
+        rest: row.try_get("rest")?,
+    })
+}
+#[async_trait]
+impl MeasurementStorage for Postgres {
+    async fn device_ids(&self) -> Result<HashMap<String, String>, Error> {
+        let devices = sqlx::query!("SELECT id, name FROM devices;").fetch_all(&self.pool).await?;
+        Ok(devices.into_iter().map(|device| (device.name, device.id)).collect())
+    }
+
+    async fn register_device(&self, id: &str, name: &str) -> Result<(), Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!(
+            r#"
+                INSERT INTO devices (id, name) VALUES ($1, $2)
+                ON CONFLICT (id) DO UPDATE SET name = excluded.name;
+            "#,
+            id, name,
+        ).execute(&mut tx).await?;
+        // measurements from before multiple devices were supported
+        if self.legacy_device.as_deref() == Some(name) {
+            sqlx::query!("UPDATE measurements SET device = $1 WHERE device = '';", id).execute(&mut tx).await?;
+            sqlx::query!("UPDATE rollups SET device = $1 WHERE device = '';", id).execute(&mut tx).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn timestamps(&self, device: &str) -> Result<Timestamps, Error> {
+        Ok(sqlx::query_as!(
+            Timestamps,
+            "SELECT extract(epoch from min(timestamp))::int8 * 1000 as first, extract(epoch from max(timestamp))::int8 * 1000 as last FROM measurements WHERE device = $1;",
+            device,
+        ).fetch_one(&self.pool).await?)
+    }
+
+    async fn data(&self, device: &str, first: u64, last: u64, _combine_datapoints: u64, combine_millis: u64) -> Result<Vec<Measurement>, Error> {
+        // the continuous aggregate is hourly
+        if self.timescale && combine_millis >= HOUR_MILLIS {
+            return self.timescale_data(device, first, last, combine_millis).await;
+        }
+        if let Some(resolution) = self.rollup_resolution(combine_millis) {
+            return self.rollup_data(device, first, last, resolution, combine_millis, Aggregation::Avg, None).await;
+        }
+        self.raw_data(device, first, last, combine_millis, Aggregation::Avg, None).await
+    }
+
+    async fn aggregate(&self, device: &str, first: u64, last: u64, combine_datapoints: u64, combine_millis: u64, aggregations: &[Aggregation], fields: Option<&[String]>) -> Result<Vec<Vec<Measurement>>, Error> {
+        // all series have to come from the same source to have the same buckets
+        let rollup_resolution = self.rollup_resolution(combine_millis)
+            .filter(|_| !self.timescale && aggregations.iter().all(|aggregation| ROLLUP_AGGREGATIONS.contains(aggregation)));
+        let only_averages = aggregations.iter().all(|&aggregation| aggregation == Aggregation::Avg);
+        let mut series = Vec::new();
+        for &aggregation in aggregations {
+            series.push(match rollup_resolution {
+                Some(resolution) => self.rollup_data(device, first, last, resolution, combine_millis, aggregation, fields).await?,
+                // the continuous aggregate is faster than selecting fields from the raw measurements
+                None if only_averages && (self.timescale || fields.is_none()) => self.data(device, first, last, combine_datapoints, combine_millis).await?,
+                None => self.raw_data(device, first, last, combine_millis, aggregation, fields).await?,
+            });
+        }
+        Ok(series)
+    }
+
+    async fn raw_rows(&self, device: &str, first: u64, last: u64, fields: Option<&[String]>, rows: mpsc::Sender<Measurement>) -> Result<(), Error> {
+        // fields which aren't needed are selected as NULL
+        let column = |column: &str, typ: &str| if is_selected(fields, column) {
+            column.to_string()
+        } else {
+            format!("NULL::{} as {}", typ, column)
+        };
+        let columns: Vec<_> = FLOAT_FIELDS.iter()
+            .map(|field| column(field, "float8"))
+            .chain([column("sound", "float8"), column("uptime", "int8")])
+            .collect();
+        // paged by the exact timestamp in µs of the last row, slow clients would hold a connection otherwise
+        let query = format!(
+            r#"
+                SELECT (extract(epoch from timestamp) * 1000)::int8 as timestamp, (extract(epoch from timestamp) * 1000000)::int8 as after, {}, {}
+                FROM measurements
+                WHERE device = $1 AND timestamp >= to_timestamp($2::float8 / 1000) AND timestamp <= to_timestamp($3::float8 / 1000)
+                    AND ($4::int8 IS NULL OR timestamp > 'epoch'::timestamptz + $4 * interval '1 microsecond')
+                -- not the selected timestamp in ms
+                ORDER BY measurements.timestamp
+                LIMIT $5;
+            "#,
+            columns.join(", "),
+            if needs_rest(fields) { "rest" } else { "NULL::jsonb as rest" },
+        );
+        let mut after: Option<i64> = None;
+        loop {
+            let page = sqlx::query(&query)
+                .bind(device)
+                .bind(first as f64)
+                .bind(last as f64)
+                .bind(after)
+                .bind(RAW_CHUNK)
+                .fetch_all(&self.pool).await?;
+            let complete = (page.len() as i64) < RAW_CHUNK;
+            for row in page {
+                after = Some(row.try_get("after")?);
+                if rows.send(measurement(&row)?).await.is_err() {
+                    return Ok(());
+                }
+            }
+            if complete {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn last_timestamps(&self, device: &str) -> Result<Option<(FilePath, u64)>, Error> {
+        let last = sqlx::query!(
+            r#"
+                SELECT extract(epoch from max(timestamp))::int8 * 1000 as timestamp
+                FROM measurements
+                WHERE device = $1 AND persistent;
+            "#,
+            device,
+        ).fetch_one(&self.pool).await?;
+        Ok(last.timestamp.map(resume_from))
+    }
+
+    async fn store_entries(&self, device: &str, entries: &mut (dyn Stream<Item = (FilePath, Vec<Data14>)> + Unpin + Send), last_timestamp: Option<u64>) -> Result<u64, Error> {
+        let mut stored = 0;
+        while let Some((_file, entries)) = entries.next().await {
+            // the timestamps are stored in seconds, of entries in the same second only the first is inserted,
+            // because a statement can't insert a row and update it with `ON CONFLICT`
+            let mut seconds = HashSet::new();
+            let entries: Vec<_> = entries.into_iter()
+                .filter(|entry| Some(entry.data11.timestamp) > last_timestamp)
+                .filter(|entry| seconds.insert(entry.data11.timestamp / 1000))
+                .collect();
+            if entries.is_empty() {
+                continue;
+            }
+            // one multi-row insert per file, each array is a column
+            // rows which are new or were manual readouts are added to the rollups
+            let column = |f: fn(&Data14) -> f64| entries.iter().map(f).collect::<Vec<_>>();
+            let optional = |f: fn(&Data14) -> Option<f64>| entries.iter().map(f).collect::<Vec<_>>();
+            stored += sqlx::query!(
+                r#"
+                    WITH stored AS (
+                    INSERT INTO measurements (
+                        timestamp, persistent, health, performance, tvoc, humidity, humidity_abs, temperature, dewpt, sound,
+                        pressure, no2, co, co2, pm1, pm2_5, pm10, oxygen, o3, so2, device,
+                        uptime, measuretime, status, bat, door_event, window_open, humidity_abs_delta, co2_delta,
+                        cnt0_3, cnt0_5, cnt1, cnt2_5, cnt5, cnt10, typ_ps, rest
+                    )
+                    SELECT
+                        to_timestamp(timestamp / 1000), true, health, performance, tvoc, humidity, humidity_abs, temperature, dewpt, sound,
+                        pressure, no2, co, co2, pm1, pm2_5, pm10, oxygen, o3, so2, $20,
+                        uptime, measuretime, status::jsonb, bat, door_event, window_open, humidity_abs_delta, co2_delta,
+                        cnt0_3, cnt0_5, cnt1, cnt2_5, cnt5, cnt10, typ_ps, rest::jsonb
+                    FROM UNNEST(
+                        $1::int8[], $2::float8[], $3::float8[], $4::float8[], $5::float8[], $6::float8[], $7::float8[], $8::float8[], $9::float8[],
+                        $10::float8[], $11::float8[], $12::float8[], $13::float8[], $14::float8[], $15::float8[], $16::float8[], $17::float8[], $18::float8[], $19::float8[],
+                        $21::int8[], $22::float8[], $23::text[], $24::float8[], $25::float8[], $26::float8[], $27::float8[], $28::float8[],
+                        $29::float8[], $30::float8[], $31::float8[], $32::float8[], $33::float8[], $34::float8[], $35::float8[], $36::text[]
+                    ) AS entries (
+                        timestamp, health, performance, tvoc, humidity, humidity_abs, temperature, dewpt, sound,
+                        pressure, no2, co, co2, pm1, pm2_5, pm10, oxygen, o3, so2,
+                        uptime, measuretime, status, bat, door_event, window_open, humidity_abs_delta, co2_delta,
+                        cnt0_3, cnt0_5, cnt1, cnt2_5, cnt5, cnt10, typ_ps, rest
+                    )
+                    ON CONFLICT (device, timestamp) DO UPDATE SET persistent = true WHERE NOT measurements.persistent
+                    RETURNING *
+                    ), rollup AS (
+                        INSERT INTO rollups (device, resolution, bucket, field, min, max, sum, count)
+                        SELECT
+                            device, resolution, date_bin(make_interval(secs => resolution), timestamp, 'epoch') as bucket, field,
+                            min(value), max(value), sum(CASE field WHEN 'sound' THEN power(10, value / 10) ELSE value END), count(*)
+                        FROM stored, measurement_fields(to_jsonb(stored) - 'samples'), unnest(ARRAY[3600, 86400]) AS resolution
+                        GROUP BY device, resolution, bucket, field
+                        ON CONFLICT (device, resolution, bucket, field) DO UPDATE SET
+                            min = least(rollups.min, excluded.min),
+                            max = greatest(rollups.max, excluded.max),
+                            sum = rollups.sum + excluded.sum,
+                            count = rollups.count + excluded.count
+                    )
+                    SELECT count(*) as "stored!" FROM stored;
+                "#,
+                &entries.iter().map(|entry| entry.data11.timestamp as i64).collect::<Vec<_>>(),
+                &column(|entry| entry.data11.health),
+                &column(|entry| entry.data11.performance),
+                &optional(|entry| Some(entry.data11.tvoc?[0])),
+                &column(|entry| entry.data11.humidity[0]),
+                &column(|entry| entry.data11.humidity_abs[0]),
+                &column(|entry| entry.data11.temperature[0]),
+                &column(|entry| entry.data11.dewpt[0]),
+                &column(|entry| entry.data11.sound[0]),
+                &column(|entry| entry.data11.pressure[0]),
+                &optional(|entry| Some(entry.data11.no2?[0])),
+                &optional(|entry| Some(entry.data11.co?[0])),
+                &column(|entry| entry.data11.co2[0]),
+                &column(|entry| entry.data11.pm1[0]),
+                &column(|entry| entry.data11.pm2_5[0]),
+                &column(|entry| entry.data11.pm10[0]),
+                &column(|entry| entry.oxygen[0]),
+                &optional(|entry| Some(entry.o3?[0])),
+                &optional(|entry| Some(entry.so2?[0])),
+                device,
+                &entries.iter().map(|entry| entry.data11.uptime as i64).collect::<Vec<_>>(),
+                &column(|entry| entry.data11.measuretime),
+                &entries.iter().map(|entry| serde_json::to_string(&entry.data11.status).unwrap()).collect::<Vec<_>>(),
+                &optional(|entry| Some(entry.data11.bat?[0])),
+                &optional(|entry| entry.data11.door_event),
+                &optional(|entry| entry.data11.window_open),
+                &optional(|entry| entry.data11.humidity_abs_delta),
+                &optional(|entry| entry.data11.co2_delta),
+                &column(|entry| entry.data11.cnt0_3[0]),
+                &column(|entry| entry.data11.cnt0_5[0]),
+                &column(|entry| entry.data11.cnt1[0]),
+                &column(|entry| entry.data11.cnt2_5[0]),
+                &column(|entry| entry.data11.cnt5[0]),
+                &column(|entry| entry.data11.cnt10[0]),
+                &column(|entry| entry.data11.typ_ps),
+                &entries.iter().map(|entry| serde_json::to_string(&entry.data11.rest).unwrap()).collect::<Vec<_>>(),
+            ).fetch_one(&self.pool).await?.stored as u64;
+        }
+        Ok(stored)
+    }
+
+    async fn store_manual_readout(&self, data: &Data14) -> Result<(), Error> {
+        let Data14 {
+            data11: Data11 {
+                deviceid, status, uptime, health, performance, measuretime, timestamp, bat,
+                door_event, window_open, tvoc, humidity, humidity_abs, humidity_abs_delta, temperature, dewpt, sound,
+                pressure, no2, co, co2, co2_delta, pm1, pm2_5, pm10, cnt0_3, cnt0_5, cnt1, cnt2_5, cnt5,
+                cnt10, typ_ps, rest
+            }, oxygen, o3, so2
+        } = data;
+        sqlx::query!(
+            r#"
+                INSERT INTO measurements (
+                    timestamp, persistent, health, performance, tvoc, humidity, humidity_abs, temperature, dewpt, sound,
+                    pressure, no2, co, co2, pm1, pm2_5, pm10, oxygen, o3, so2, device,
+                    uptime, measuretime, status, bat, door_event, window_open, humidity_abs_delta, co2_delta,
+                    cnt0_3, cnt0_5, cnt1, cnt2_5, cnt5, cnt10, typ_ps, rest
+                ) VALUES (
+                    to_timestamp($1 / 1000), false, $2, $3, $4, $5, $6, $7, $8, $9,
+                    $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20,
+                    $21, $22, $23, $24, $25, $26, $27, $28,
+                    $29, $30, $31, $32, $33, $34, $35, $36
+                )
+                ON CONFLICT DO NOTHING
+                ;
+            "#,
+            *timestamp as i64, health, performance, tvoc.map(|tvoc| tvoc[0]), humidity[0],
+            humidity_abs[0], temperature[0], dewpt[0], sound[0], pressure[0],
+            no2.map(|no2| no2[0]), co.map(|co| co[0]), co2[0], pm1[0], pm2_5[0],
+            pm10[0], oxygen[0], o3.map(|o3| o3[0]), so2.map(|so2| so2[0]), deviceid,
+            *uptime as i64, measuretime, serde_json::to_value(status).unwrap(), bat.map(|bat| bat[0]), door_event,
+            window_open, humidity_abs_delta, co2_delta, cnt0_3[0], cnt0_5[0], cnt1[0],
+            cnt2_5[0], cnt5[0], cnt10[0], typ_ps, serde_json::to_value(rest).unwrap(),
+        ).execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn apply_retention(&self, cutoffs: &RetentionCutoffs, dry_run: bool) -> Result<RetentionReport, Error> {
+        let mut report = RetentionReport::default();
+        let manual_readouts_before = cutoffs.manual_readouts_before as f64;
+        report.manual_readouts = if dry_run {
+            sqlx::query!(
+                r#"SELECT count(*) as "count!" FROM measurements WHERE NOT persistent AND timestamp < to_timestamp($1::float8 / 1000);"#,
+                manual_readouts_before,
+            ).fetch_one(&self.pool).await?.count as u64
+        } else {
+            sqlx::query!(r#"
+                DELETE FROM measurements
+                WHERE NOT persistent AND timestamp < to_timestamp($1::float8 / 1000);
+            "#, manual_readouts_before).execute(&self.pool).await?.rows_affected()
+        };
+
+        let before = match cutoffs.downsample_before {
+            Some(before) => before as f64,
+            None => return Ok(report),
+        };
+        let bucket_millis = cutoffs.downsample_millis as f64;
+        // buckets consisting of a single row were already downsampled
+        let buckets = sqlx::query!(
+            r#"
+                SELECT coalesce(sum(count), 0)::int8 as "rows!", count(*) as "buckets!"
+                FROM (
+                    SELECT count(*)
+                    FROM measurements
+                    WHERE persistent AND timestamp < to_timestamp($1::float8 / 1000)
+                    GROUP BY device, date_bin(make_interval(secs => $2::float8 / 1000), timestamp, 'epoch')
+                    HAVING count(*) > 1
+                ) buckets;
+            "#,
+            before, bucket_millis,
+        ).fetch_one(&self.pool).await?;
+        report.downsampled = buckets.rows as u64;
+        report.buckets = buckets.buckets as u64;
+        if dry_run || report.buckets == 0 {
+            return Ok(report);
+        }
+
+        // rows which were already downsampled are weighted by the number of readouts they average
+        let averages: Vec<_> = FLOAT_FIELDS.iter()
+            .map(|column| format!("{} as {}", weighted_avg_sql(column, column), column))
+            .chain([format!("10 * log10({}) as sound", weighted_avg_sql("power(10, sound / 10)", "sound"))])
+            .collect();
+        // the averages are computed into a temporary table first, because a row may be replaced by one with the same timestamp
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(&format!(
+            r#"
+                CREATE TEMPORARY TABLE downsampled ON COMMIT DROP AS
+                WITH selected AS (
+                    SELECT *, date_bin(make_interval(secs => $2::float8 / 1000), timestamp, 'epoch') as bucket
+                    FROM measurements
+                    WHERE persistent AND timestamp < to_timestamp($1::float8 / 1000)
+                ), buckets AS (
+                    SELECT device, bucket FROM selected GROUP BY device, bucket HAVING count(*) > 1
+                ), rest AS (
+                    SELECT device, bucket, jsonb_object_agg(key, value) as rest
+                    FROM (
+                        SELECT device, bucket, key,
+                            sum(CASE jsonb_typeof(value) WHEN 'array' THEN (value ->> 0)::float8 ELSE (value #>> '{{}}')::float8 END * samples)
+                                / sum(samples) as value
+                        FROM selected JOIN buckets USING (device, bucket) CROSS JOIN LATERAL jsonb_each(selected.rest)
+                        WHERE jsonb_typeof(value) = 'number' OR jsonb_typeof(value -> 0) = 'number'
+                        GROUP BY device, bucket, key
+                    ) averages
+                    GROUP BY device, bucket
+                )
+                SELECT
+                    device, bucket as timestamp, {}, max(uptime) as uptime,
+                    (array_agg(status ORDER BY timestamp DESC))[1] as status,
+                    sum(samples)::int4 as samples, coalesce(rest.rest, '{{}}') as rest
+                FROM selected JOIN buckets USING (device, bucket) LEFT JOIN rest USING (device, bucket)
+                GROUP BY device, bucket, rest.rest;
+            "#,
+            averages.join(", "),
+        ))
+            .bind(before)
+            .bind(bucket_millis)
+            .execute(&mut tx).await?;
+        sqlx::query(r#"
+            DELETE FROM measurements USING downsampled
+            WHERE measurements.persistent AND measurements.device = downsampled.device
+                AND measurements.timestamp >= downsampled.timestamp
+                AND measurements.timestamp < downsampled.timestamp + make_interval(secs => $1::float8 / 1000);
+        "#)
+            .bind(bucket_millis)
+            .execute(&mut tx).await?;
+        sqlx::query(r#"
+            INSERT INTO measurements (
+                timestamp, persistent, health, performance, tvoc, humidity, humidity_abs, temperature, dewpt, sound,
+                pressure, no2, co, co2, pm1, pm2_5, pm10, oxygen, o3, so2, device,
+                uptime, measuretime, status, bat, door_event, window_open, humidity_abs_delta, co2_delta,
+                cnt0_3, cnt0_5, cnt1, cnt2_5, cnt5, cnt10, typ_ps, rest, samples
+            )
+            SELECT
+                timestamp, true, health, performance, tvoc, humidity, humidity_abs, temperature, dewpt, sound,
+                pressure, no2, co, co2, pm1, pm2_5, pm10, oxygen, o3, so2, device,
+                uptime, measuretime, status, bat, door_event, window_open, humidity_abs_delta, co2_delta,
+                cnt0_3, cnt0_5, cnt1, cnt2_5, cnt5, cnt10, typ_ps, rest, samples
+            FROM downsampled;
+        "#).execute(&mut tx).await?;
+        tx.commit().await?;
+        Ok(report)
+    }
+
+    async fn set_file_state(&self, device: &str, files: &[FilePath], state: SyncState, error: Option<&str>) -> Result<(), Error> {
+        let failed = state == SyncState::Failed;
+        let mut tx = self.pool.begin().await?;
+        for file in files {
+            sqlx::query!(
+                r#"
+                    INSERT INTO file_sync (device, year, month, day, timestamp, state, attempts, error)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                    ON CONFLICT (device, year, month, day, timestamp) DO UPDATE SET
+                        state = excluded.state,
+                        attempts = file_sync.attempts + excluded.attempts,
+                        -- keep the last error while the file is retried
+                        error = CASE WHEN excluded.state = 'pending' THEN file_sync.error ELSE excluded.error END,
+                        updated = now();
+                "#,
+                device, file.year as i32, file.month as i32, file.day as i32, file.timestamp as i64,
+                state.as_str(), failed as i32, error,
+            ).execute(&mut tx).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn unsynced_files(&self, device: &str) -> Result<Vec<FileSync>, Error> {
+        let files = sqlx::query!(
+            r#"
+                SELECT year, month, day, timestamp, state, attempts, error
+                FROM file_sync
+                WHERE device = $1 AND state <> 'succeeded'
+                ORDER BY year, month, day, timestamp;
+            "#,
+            device,
+        ).fetch_all(&self.pool).await?;
+        Ok(files.into_iter()
+            .map(|file| FileSync {
+                file: FilePath { year: file.year as u16, month: file.month as u8, day: file.day as u8, timestamp: file.timestamp as u64 },
+                state: SyncState::parse(&file.state),
+                attempts: file.attempts as u32,
+                error: file.error,
+            })
+            .collect())
+    }
+
+    fn pool_stats(&self) -> Option<(u32, usize)> {
+        Some((self.pool.size(), self.pool.num_idle()))
+    }
+}
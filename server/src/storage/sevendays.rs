@@ -0,0 +1,304 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::sync::Mutex;
+use async_trait::async_trait;
+use chrono::{Datelike, Duration, Utc};
+use futures::{Stream, StreamExt};
+use airq::{Data14, FilePath};
+use serde::{Serialize, Deserialize};
+use tokio::sync::mpsc;
+use crate::{Timestamps, Measurement};
+use crate::acoustics;
+use crate::fetch_data::Error;
+use super::{Aggregation, FileSync, MeasurementStorage, RetentionCutoffs, RetentionReport, SyncState, aggregate_rest, is_selected, needs_rest};
+
+#[derive(Default)]
+pub struct Sevendays {
+    devices: Mutex<HashMap<String, String>>,
+    last_file: Mutex<HashMap<String, FilePath>>,
+    data: Mutex<Vec<Data14>>,
+    path: String,
+    /// name of the device the last file from before multiple devices were supported belongs to
+    legacy_device: Option<String>,
+    legacy_last_file: Mutex<Option<FilePath>>,
+    /// download state of the files by device, only kept in memory
+    files: Mutex<HashMap<String, BTreeMap<FilePath, FileSync>>>,
+}
+
+/// Contents of the JSON file.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Stored {
+    State {
+        #[serde(default)]
+        devices: HashMap<String, String>,
+        last_file: LastFile,
+        data: Vec<Data14>,
+    },
+    /// only the measurements were written before multiple devices were supported
+    Measurements(Vec<Data14>),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum LastFile {
+    /// last file by device id
+    Devices(HashMap<String, FilePath>),
+    /// last file of the only device before multiple devices were supported
+    Legacy(Option<FilePath>),
+}
+
+impl Sevendays {
+    pub fn open(path: &str, legacy_device: Option<String>) -> Sevendays {
+        let stored = match File::open(path) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))
+                .unwrap_or_else(|e| panic!("{} isn't a valid sevendays file: {}", path, e)),
+            Err(_) => Stored::Measurements(Vec::new()),
+        };
+        let (devices, last_file, legacy_last_file, data) = match stored {
+            Stored::State { devices, last_file: LastFile::Devices(last_file), data } => (devices, last_file, None, data),
+            Stored::State { devices, last_file: LastFile::Legacy(last_file), data } => (devices, HashMap::new(), last_file, data),
+            Stored::Measurements(data) => (HashMap::new(), HashMap::new(), None, data),
+        };
+        if legacy_last_file.is_some() && legacy_device.is_none() {
+            eprintln!("the last downloaded file in {} belongs to no device, as multiple devices are configured", path);
+        }
+        Sevendays {
+            devices: Mutex::new(devices),
+            last_file: Mutex::new(last_file),
+            data: Mutex::new(data),
+            path: path.to_string(),
+            legacy_device,
+            legacy_last_file: Mutex::new(legacy_last_file),
+            files: Mutex::default(),
+        }
+    }
+
+    /// Combines every `combine_datapoints` measurements of the device with the aggregation, only `fields` if given.
+    fn aggregated(&self, device: &str, first: u64, last: u64, combine_datapoints: u64, aggregation: Aggregation, fields: Option<&[String]>) -> Vec<Measurement> {
+        let data: Vec<_> = self.data.lock().unwrap().iter()
+            .filter(|data| data.data11.deviceid == device)
+            .cloned()
+            .collect();
+        data.chunks(combine_datapoints as usize)
+            .map(|data| {
+                let agg = |field: &str, f: &dyn Fn(&Data14) -> Option<f64>| {
+                    if !is_selected(fields, field) {
+                        return None;
+                    }
+                    let values: Vec<_> = data.iter().filter_map(f).collect();
+                    aggregation.apply(&values)
+                };
+                Measurement {
+                    timestamp: Some(data[0].data11.timestamp as i64),
+                    health: agg("health", &|entry| Some(entry.data11.health)),
+                    performance: agg("performance", &|entry| Some(entry.data11.performance)),
+                    tvoc: agg("tvoc", &|entry| Some(entry.data11.tvoc?[0])),
+                    humidity: agg("humidity", &|entry| Some(entry.data11.humidity[0])),
+                    humidity_abs: agg("humidity_abs", &|entry| Some(entry.data11.humidity_abs[0])),
+                    temperature: agg("temperature", &|entry| Some(entry.data11.temperature[0])),
+                    dewpt: agg("dewpt", &|entry| Some(entry.data11.dewpt[0])),
+                    sound: match aggregation {
+                        _ if !is_selected(fields, "sound") => None,
+                        Aggregation::Avg => Some(acoustics::energetic_mean(data.iter().map(|entry| entry.data11.sound[0]))),
+                        _ => agg("sound", &|entry| Some(entry.data11.sound[0])),
+                    },
+                    pressure: agg("pressure", &|entry| Some(entry.data11.pressure[0])),
+                    no2: agg("no2", &|entry| Some(entry.data11.no2?[0])),
+                    co: agg("co", &|entry| Some(entry.data11.co?[0])),
+                    co2: agg("co2", &|entry| Some(entry.data11.co2[0])),
+                    pm1: agg("pm1", &|entry| Some(entry.data11.pm1[0])),
+                    pm2_5: agg("pm2_5", &|entry| Some(entry.data11.pm2_5[0])),
+                    pm10: agg("pm10", &|entry| Some(entry.data11.pm10[0])),
+                    oxygen: agg("oxygen", &|entry| Some(entry.oxygen[0])),
+                    o3: agg("o3", &|entry| Some(entry.o3?[0])),
+                    so2: agg("so2", &|entry| Some(entry.so2?[0])),
+                    uptime: match aggregation {
+                        _ if !is_selected(fields, "uptime") => None,
+                        Aggregation::Avg => data.iter().map(|entry| entry.data11.uptime as i64).max(),
+                        _ => agg("uptime", &|entry| Some(entry.data11.uptime as f64)).map(|uptime| uptime as i64),
+                    },
+                    measuretime: agg("measuretime", &|entry| Some(entry.data11.measuretime)),
+                    bat: agg("bat", &|entry| Some(entry.data11.bat?[0])),
+                    door_event: agg("door_event", &|entry| entry.data11.door_event),
+                    window_open: agg("window_open", &|entry| entry.data11.window_open),
+                    humidity_abs_delta: agg("humidity_abs_delta", &|entry| entry.data11.humidity_abs_delta),
+                    co2_delta: agg("co2_delta", &|entry| entry.data11.co2_delta),
+                    cnt0_3: agg("cnt0_3", &|entry| Some(entry.data11.cnt0_3[0])),
+                    cnt0_5: agg("cnt0_5", &|entry| Some(entry.data11.cnt0_5[0])),
+                    cnt1: agg("cnt1", &|entry| Some(entry.data11.cnt1[0])),
+                    cnt2_5: agg("cnt2_5", &|entry| Some(entry.data11.cnt2_5[0])),
+                    cnt5: agg("cnt5", &|entry| Some(entry.data11.cnt5[0])),
+                    cnt10: agg("cnt10", &|entry| Some(entry.data11.cnt10[0])),
+                    typ_ps: agg("typ_ps", &|entry| Some(entry.data11.typ_ps)),
+                    rest: needs_rest(fields).then(|| aggregate_rest(data.iter().map(|entry| &entry.data11.rest), aggregation)),
+                }
+            })
+            .skip_while(|data| data.timestamp.unwrap() < first as i64)
+            .take_while(|data| data.timestamp.unwrap() <= last as i64)
+            .collect()
+    }
+}
+#[async_trait]
+impl MeasurementStorage for Sevendays {
+    async fn device_ids(&self) -> Result<HashMap<String, String>, Error> {
+        Ok(self.devices.lock().unwrap().clone())
+    }
+
+    async fn register_device(&self, id: &str, name: &str) -> Result<(), Error> {
+        self.devices.lock().unwrap().insert(name.to_string(), id.to_string());
+        // the last file from before multiple devices were supported
+        if self.legacy_device.as_deref() == Some(name) {
+            if let Some(legacy_last_file) = self.legacy_last_file.lock().unwrap().take() {
+                self.last_file.lock().unwrap().entry(id.to_string()).or_insert(legacy_last_file);
+            }
+        }
+        Ok(())
+    }
+
+    async fn timestamps(&self, device: &str) -> Result<Timestamps, Error> {
+        let data = self.data.lock().unwrap();
+        let mut data = data.iter().filter(|data| data.data11.deviceid == device);
+        let first = data.next().map(|data| data.data11.timestamp as i64);
+        let last = data.last().map(|data| data.data11.timestamp as i64).or(first);
+        Ok(Timestamps { first, last })
+    }
+
+    async fn data(&self, device: &str, first: u64, last: u64, combine_datapoints: u64, _combine_millis: u64) -> Result<Vec<Measurement>, Error> {
+        Ok(self.aggregated(device, first, last, combine_datapoints, Aggregation::Avg, None))
+    }
+
+    async fn aggregate(&self, device: &str, first: u64, last: u64, combine_datapoints: u64, _combine_millis: u64, aggregations: &[Aggregation], fields: Option<&[String]>) -> Result<Vec<Vec<Measurement>>, Error> {
+        Ok(aggregations.iter()
+            .map(|&aggregation| self.aggregated(device, first, last, combine_datapoints, aggregation, fields))
+            .collect())
+    }
+
+    async fn raw_rows(&self, device: &str, first: u64, last: u64, fields: Option<&[String]>, rows: mpsc::Sender<Measurement>) -> Result<(), Error> {
+        for measurement in self.aggregated(device, first, last, 1, Aggregation::Avg, fields) {
+            if rows.send(measurement).await.is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    async fn last_timestamps(&self, device: &str) -> Result<Option<(FilePath, u64)>, Error> {
+        let last_file = self.last_file.lock().unwrap().get(device).copied();
+        let last_timestamp = self.data.lock().unwrap().iter().rev()
+            .find(|data| data.data11.deviceid == device)
+            .map(|data| data.data11.timestamp);
+        let seven_days_ago = Utc::now() - Duration::days(7);
+        let from_file = last_file.unwrap_or(FilePath {
+            year: seven_days_ago.year() as u16,
+            month: seven_days_ago.month() as u8,
+            day: seven_days_ago.day() as u8,
+            timestamp: seven_days_ago.timestamp() as u64,
+        });
+        let from_ts = match last_timestamp {
+            Some(ts) => ts,
+            None => seven_days_ago.timestamp() as u64,
+        };
+        Ok(Some((from_file, from_ts)))
+    }
+
+    async fn store_entries(&self, device: &str, entries: &mut (dyn Stream<Item = (FilePath, Vec<Data14>)> + Unpin + Send), last_timestamp: Option<u64>) -> Result<u64, Error> {
+        // collect
+        let mut collected_data = Vec::new();
+        let mut collected_last_file = FilePath {
+            year: 0,
+            month: 0,
+            day: 0,
+            timestamp: 0
+        };
+        while let Some((file, entries)) = entries.next().await {
+            // retried files are older than the last stored measurement and have no cutoff
+            collected_data.extend(entries.into_iter().filter(|data| Some(data.data11.timestamp) > last_timestamp));
+            collected_last_file = collected_last_file.max(file);
+        }
+
+        // insert the measurements which aren't stored yet
+        let mut data = self.data.lock().unwrap();
+        let mut last_file = self.last_file.lock().unwrap();
+        let mut stored: HashSet<_> = data.iter()
+            .filter(|data| data.data11.deviceid == device)
+            .map(|data| data.data11.timestamp)
+            .collect();
+        let to_add: Vec<_> = collected_data.into_iter().filter(|data| stored.insert(data.data11.timestamp)).collect();
+        let added = to_add.len() as u64;
+        data.extend(to_add);
+        let device_last_file = last_file.entry(device.to_string()).or_insert(collected_last_file);
+        *device_last_file = (*device_last_file).max(collected_last_file);
+        data.sort_unstable_by_key(|data| data.data11.timestamp);
+        let stored = Stored::State {
+            devices: self.devices.lock().unwrap().clone(),
+            last_file: LastFile::Devices(last_file.clone()),
+            data: data.clone(),
+        };
+        serde_json::to_writer(BufWriter::new(File::create(&self.path).unwrap()), &stored).unwrap();
+
+        Ok(added)
+    }
+
+    // not needed for local testing
+    async fn store_manual_readout(&self, _data: &Data14) -> Result<(), Error> { Ok(()) }
+    async fn apply_retention(&self, _cutoffs: &RetentionCutoffs, _dry_run: bool) -> Result<RetentionReport, Error> { Ok(RetentionReport::default()) }
+
+    async fn set_file_state(&self, device: &str, files: &[FilePath], state: SyncState, error: Option<&str>) -> Result<(), Error> {
+        let mut synced = self.files.lock().unwrap();
+        let synced = synced.entry(device.to_string()).or_default();
+        for &file in files {
+            let sync = synced.entry(file).or_insert(FileSync { file, state, attempts: 0, error: None });
+            sync.state = state;
+            if state != SyncState::Pending {
+                sync.error = error.map(str::to_string);
+            }
+            if state == SyncState::Failed {
+                sync.attempts += 1;
+            }
+        }
+        Ok(())
+    }
+
+    async fn unsynced_files(&self, device: &str) -> Result<Vec<FileSync>, Error> {
+        Ok(self.files.lock().unwrap().get(device).into_iter()
+            .flat_map(|files| files.values())
+            .filter(|sync| sync.state != SyncState::Succeeded)
+            .cloned()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream;
+    use super::*;
+    use super::super::test_readout as readout;
+
+    #[rocket::async_test]
+    async fn opens_files_from_before_multiple_devices() {
+        let path = std::env::temp_dir().join(format!("airq-sevendays-{}.json", std::process::id())).display().to_string();
+        let device = "0123456789abcdef";
+        let file = FilePath { year: 2020, month: 9, day: 13, timestamp: 1_600_000_000 };
+        let old = serde_json::json!({ "last_file": file, "data": [readout(1_600_000_020_000, 400., 1)] });
+        std::fs::write(&path, old.to_string()).unwrap();
+        let sevendays = Sevendays::open(&path, Some("office".to_string()));
+        sevendays.register_device(device, "office").await.unwrap();
+        assert_eq!(sevendays.last_timestamps(device).await.unwrap(), Some((file, 1_600_000_020_000)));
+
+        // the devices and last files are stored with the measurements
+        let newer = FilePath { timestamp: 1_600_000_100, ..file };
+        let entries = vec![(newer, vec![readout(1_600_000_120_000, 500., 2)])];
+        sevendays.store_entries(device, &mut stream::iter(entries), Some(1_600_000_020_000)).await.unwrap();
+        let reopened = Sevendays::open(&path, None);
+        assert_eq!(reopened.last_timestamps(device).await.unwrap(), Some((newer, 1_600_000_120_000)));
+        assert_eq!(reopened.device_ids().await.unwrap()["office"], device);
+
+        // only the measurements were written before
+        std::fs::write(&path, serde_json::to_string(&[readout(1_600_000_020_000, 400., 1)]).unwrap()).unwrap();
+        let measurements = Sevendays::open(&path, Some("office".to_string()));
+        assert_eq!(measurements.timestamps(device).await.unwrap().last, Some(1_600_000_020_000));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,620 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use async_trait::async_trait;
+use chrono::Utc;
+use futures::{Stream, StreamExt};
+use airq::{Data14, FilePath};
+use serde_json::{Map, Value};
+use tokio::sync::mpsc;
+use sqlx::{Row, SqlitePool};
+use sqlx::query::Query;
+use sqlx::sqlite::{SqliteArguments, SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
+use crate::{Timestamps, Measurement};
+use crate::fetch_data::Error;
+use super::{Aggregation, FLOAT_FIELDS, FileSync, MeasurementStorage, RetentionCutoffs, RetentionReport, SyncState, is_selected, needs_rest};
+
+/// Stores measurements in an SQLite database, for deployments where running PostgreSQL is too heavy.
+///
+/// Uses the same layout as PostgreSQL before the iot2db migration:
+/// downloaded files are tracked in `files` and manual readouts don't reference a file.
+pub struct Sqlite {
+    pool: SqlitePool,
+}
+
+impl Sqlite {
+    /// Opens the database at `path`, creating and migrating it if needed.
+    pub async fn open(path: &str) -> Result<Sqlite, Error> {
+        let options = SqliteConnectOptions::from_str(path)?
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(4)
+            .connect_with(options).await?;
+        sqlx::migrate!("migrations-sqlite").run(&pool).await?;
+        Ok(Sqlite { pool })
+    }
+
+    /// Averages the measurements in buckets of `combine_millis`, only `fields` if given.
+    ///
+    /// Rows which were downsampled are weighted by the number of readouts they average.
+    async fn averages(&self, device: &str, first: u64, last: u64, combine_millis: u64, fields: Option<&[String]>) -> Result<Vec<Measurement>, Error> {
+        // fields which aren't needed are selected as NULL
+        let column = |column: &str, aggregate: String| if is_selected(fields, column) {
+            format!("{} as {}", aggregate, column)
+        } else {
+            format!("NULL as {}", column)
+        };
+        let columns: Vec<_> = FLOAT_FIELDS.iter()
+            .map(|field| column(field, weighted_average(field)))
+            .chain([column("uptime", "max(uptime)".to_string())])
+            .collect();
+        let query = format!(
+            r#"
+                WITH selected AS (
+                    SELECT *, timestamp / ?4 as bucket
+                    FROM measurements
+                    WHERE device = ?1 AND timestamp >= ?2 AND timestamp <= ?3
+                ), rest AS (
+                    SELECT bucket, json_group_object(key, value) as rest
+                    FROM (
+                        SELECT selected.bucket, fields.key,
+                            sum(CASE fields.type WHEN 'array' THEN json_extract(fields.value, '$[0]') ELSE fields.value END * selected.samples)
+                                / sum(selected.samples) as value
+                        FROM selected, json_each(selected.rest) as fields
+                        WHERE CASE fields.type WHEN 'array' THEN json_type(fields.value, '$[0]') ELSE fields.type END IN ('integer', 'real')
+                        GROUP BY selected.bucket, fields.key
+                    )
+                    GROUP BY bucket
+                )
+                SELECT min(timestamp) as timestamp, {}, {} as sound_energy, {} as rest
+                FROM selected LEFT JOIN rest USING (bucket)
+                GROUP BY bucket
+                ORDER BY timestamp;
+            "#,
+            columns.join(", "),
+            if is_selected(fields, "sound") { weighted_average("sound_energy") } else { "NULL".to_string() },
+            if needs_rest(fields) { "coalesce(rest.rest, '{}')" } else { "'{}'" },
+        );
+        let rows = sqlx::query(&query)
+            .bind(device)
+            .bind(first as i64)
+            .bind(last as i64)
+            .bind(combine_millis as i64)
+            .fetch_all(&self.pool).await?;
+
+        rows.into_iter()
+            .map(|row| -> Result<_, Error> {
+                let rest: Map<String, Value> = serde_json::from_str(row.try_get("rest")?).unwrap_or_default();
+                let sound_energy: Option<f64> = row.try_get("sound_energy")?;
+                Ok(Measurement {
+                    timestamp: row.try_get("timestamp")?,
+                    health: row.try_get("health")?,
+                    performance: row.try_get("performance")?,
+                    tvoc: row.try_get("tvoc")?,
+                    humidity: row.try_get("humidity")?,
+                    humidity_abs: row.try_get("humidity_abs")?,
+                    temperature: row.try_get("temperature")?,
+                    dewpt: row.try_get("dewpt")?,
+                    sound: sound_energy.map(|energy| 10. * energy.log10()),
+                    pressure: row.try_get("pressure")?,
+                    no2: row.try_get("no2")?,
+                    co: row.try_get("co")?,
+                    co2: row.try_get("co2")?,
+                    pm1: row.try_get("pm1")?,
+                    pm2_5: row.try_get("pm2_5")?,
+                    pm10: row.try_get("pm10")?,
+                    oxygen: row.try_get("oxygen")?,
+                    o3: row.try_get("o3")?,
+                    so2: row.try_get("so2")?,
+                    uptime: row.try_get("uptime")?,
+                    measuretime: row.try_get("measuretime")?,
+                    bat: row.try_get("bat")?,
+                    door_event: row.try_get("door_event")?,
+                    window_open: row.try_get("window_open")?,
+                    humidity_abs_delta: row.try_get("humidity_abs_delta")?,
+                    co2_delta: row.try_get("co2_delta")?,
+                    cnt0_3: row.try_get("cnt0_3")?,
+                    cnt0_5: row.try_get("cnt0_5")?,
+                    cnt1: row.try_get("cnt1")?,
+                    cnt2_5: row.try_get("cnt2_5")?,
+                    cnt5: row.try_get("cnt5")?,
+                    cnt10: row.try_get("cnt10")?,
+                    typ_ps: row.try_get("typ_ps")?,
+                    rest: Some(Value::Object(rest)),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Returns the SQL averaging the column over the rows of a bucket,
+/// weighted by the number of readouts a downsampled row averages.
+fn weighted_average(column: &str) -> String {
+    format!("sum({0} * samples) / sum(CASE WHEN {0} IS NOT NULL THEN samples END)", column)
+}
+
+/// rows per query of `raw_rows`, the connection is released between the queries
+const RAW_CHUNK: i64 = 10_000;
+
+const INSERT: &str = r#"
+    INSERT INTO measurements (
+        device, timestamp, file, health, performance, tvoc, humidity, humidity_abs, temperature, dewpt, sound, sound_energy,
+        pressure, no2, co, co2, pm1, pm2_5, pm10, oxygen, o3, so2,
+        uptime, measuretime, status, bat, door_event, window_open, humidity_abs_delta, co2_delta,
+        cnt0_3, cnt0_5, cnt1, cnt2_5, cnt5, cnt10, typ_ps, rest
+    ) VALUES (
+        ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?,
+        ?, ?, ?, ?, ?, ?, ?, ?, ?, ?,
+        ?, ?, ?, ?, ?, ?, ?, ?,
+        ?, ?, ?, ?, ?, ?, ?, ?
+    )
+    ON CONFLICT (device, timestamp) DO NOTHING;
+"#;
+
+/// Inserts a readout unless one of the same time is stored, `file` is `None` for manual readouts.
+fn insert(data: &Data14, file: Option<i64>) -> Query<'_, sqlx::Sqlite, SqliteArguments<'_>> {
+    let d = &data.data11;
+    sqlx::query(INSERT)
+        .bind(&d.deviceid)
+        .bind(d.timestamp as i64)
+        .bind(file)
+        .bind(d.health)
+        .bind(d.performance)
+        .bind(d.tvoc.map(|tvoc| tvoc[0]))
+        .bind(d.humidity[0])
+        .bind(d.humidity_abs[0])
+        .bind(d.temperature[0])
+        .bind(d.dewpt[0])
+        .bind(d.sound[0])
+        .bind(10f64.powf(d.sound[0] / 10.))
+        .bind(d.pressure[0])
+        .bind(d.no2.map(|no2| no2[0]))
+        .bind(d.co.map(|co| co[0]))
+        .bind(d.co2[0])
+        .bind(d.pm1[0])
+        .bind(d.pm2_5[0])
+        .bind(d.pm10[0])
+        .bind(data.oxygen[0])
+        .bind(data.o3.map(|o3| o3[0]))
+        .bind(data.so2.map(|so2| so2[0]))
+        .bind(d.uptime as i64)
+        .bind(d.measuretime)
+        .bind(serde_json::to_string(&d.status).unwrap())
+        .bind(d.bat.map(|bat| bat[0]))
+        .bind(d.door_event)
+        .bind(d.window_open)
+        .bind(d.humidity_abs_delta)
+        .bind(d.co2_delta)
+        .bind(d.cnt0_3[0])
+        .bind(d.cnt0_5[0])
+        .bind(d.cnt1[0])
+        .bind(d.cnt2_5[0])
+        .bind(d.cnt5[0])
+        .bind(d.cnt10[0])
+        .bind(d.typ_ps)
+        .bind(serde_json::to_string(&d.rest).unwrap())
+}
+
+#[async_trait]
+impl MeasurementStorage for Sqlite {
+    async fn device_ids(&self) -> Result<HashMap<String, String>, Error> {
+        let devices = sqlx::query("SELECT id, name FROM devices;").fetch_all(&self.pool).await?;
+        devices.into_iter()
+            .map(|device| -> Result<_, Error> { Ok((device.try_get("name")?, device.try_get("id")?)) })
+            .collect()
+    }
+
+    async fn register_device(&self, id: &str, name: &str) -> Result<(), Error> {
+        sqlx::query("INSERT INTO devices (id, name) VALUES (?, ?) ON CONFLICT (id) DO UPDATE SET name = excluded.name;")
+            .bind(id)
+            .bind(name)
+            .execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn timestamps(&self, device: &str) -> Result<Timestamps, Error> {
+        let row = sqlx::query("SELECT min(timestamp) as first, max(timestamp) as last FROM measurements WHERE device = ?;")
+            .bind(device)
+            .fetch_one(&self.pool).await?;
+        Ok(Timestamps {
+            first: row.try_get("first")?,
+            last: row.try_get("last")?,
+        })
+    }
+
+    async fn data(&self, device: &str, first: u64, last: u64, _combine_datapoints: u64, combine_millis: u64) -> Result<Vec<Measurement>, Error> {
+        self.averages(device, first, last, combine_millis, None).await
+    }
+
+    async fn aggregate(&self, device: &str, first: u64, last: u64, _combine_datapoints: u64, combine_millis: u64, aggregations: &[Aggregation], fields: Option<&[String]>) -> Result<Vec<Vec<Measurement>>, Error> {
+        let mut series = Vec::new();
+        for &aggregation in aggregations {
+            if aggregation != Aggregation::Avg {
+                return Err(Error::UnsupportedAggregation(aggregation.as_str()));
+            }
+            series.push(self.averages(device, first, last, combine_millis, fields).await?);
+        }
+        Ok(series)
+    }
+
+    async fn raw_rows(&self, device: &str, first: u64, last: u64, fields: Option<&[String]>, rows: mpsc::Sender<Measurement>) -> Result<(), Error> {
+        // fields which aren't needed are selected as NULL
+        let columns: Vec<_> = FLOAT_FIELDS.iter().chain(&["sound", "uptime"])
+            .map(|column| if is_selected(fields, column) {
+                column.to_string()
+            } else {
+                format!("NULL as {}", column)
+            })
+            .collect();
+        // paged by the timestamp of the last row, slow clients would hold a connection otherwise
+        let query = format!(
+            "SELECT timestamp, {}, {} FROM measurements WHERE device = ? AND timestamp >= ? AND timestamp <= ? ORDER BY timestamp LIMIT ?;",
+            columns.join(", "),
+            if needs_rest(fields) { "rest" } else { "NULL as rest" },
+        );
+        let mut from = first as i64;
+        loop {
+            let page = sqlx::query(&query)
+                .bind(device)
+                .bind(from)
+                .bind(last as i64)
+                .bind(RAW_CHUNK)
+                .fetch_all(&self.pool).await?;
+            let complete = (page.len() as i64) < RAW_CHUNK;
+            for row in page {
+                let rest: Option<&str> = row.try_get("rest")?;
+                let measurement = Measurement {
+                    timestamp: row.try_get("timestamp")?,
+                    health: row.try_get("health")?,
+                    performance: row.try_get("performance")?,
+                    tvoc: row.try_get("tvoc")?,
+                    humidity: row.try_get("humidity")?,
+                    humidity_abs: row.try_get("humidity_abs")?,
+                    temperature: row.try_get("temperature")?,
+                    dewpt: row.try_get("dewpt")?,
+                    sound: row.try_get("sound")?,
+                    pressure: row.try_get("pressure")?,
+                    no2: row.try_get("no2")?,
+                    co: row.try_get("co")?,
+                    co2: row.try_get("co2")?,
+                    pm1: row.try_get("pm1")?,
+                    pm2_5: row.try_get("pm2_5")?,
+                    pm10: row.try_get("pm10")?,
+                    oxygen: row.try_get("oxygen")?,
+                    o3: row.try_get("o3")?,
+                    so2: row.try_get("so2")?,
+                    uptime: row.try_get("uptime")?,
+                    measuretime: row.try_get("measuretime")?,
+                    bat: row.try_get("bat")?,
+                    door_event: row.try_get("door_event")?,
+                    window_open: row.try_get("window_open")?,
+                    humidity_abs_delta: row.try_get("humidity_abs_delta")?,
+                    co2_delta: row.try_get("co2_delta")?,
+                    cnt0_3: row.try_get("cnt0_3")?,
+                    cnt0_5: row.try_get("cnt0_5")?,
+                    cnt1: row.try_get("cnt1")?,
+                    cnt2_5: row.try_get("cnt2_5")?,
+                    cnt5: row.try_get("cnt5")?,
+                    cnt10: row.try_get("cnt10")?,
+                    typ_ps: row.try_get("typ_ps")?,
+                    rest: rest.and_then(|rest| serde_json::from_str(rest).ok()),
+                };
+                from = measurement.timestamp.unwrap_or_default() + 1;
+                if rows.send(measurement).await.is_err() {
+                    return Ok(());
+                }
+            }
+            if complete {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn last_timestamps(&self, device: &str) -> Result<Option<(FilePath, u64)>, Error> {
+        let last = sqlx::query(r#"
+            SELECT files.year, files.month, files.day, files.timestamp as file_timestamp, measurements.timestamp as measurement_timestamp
+            FROM files, measurements
+            WHERE measurements.file = files.id AND measurements.device = ?
+            ORDER BY measurements.timestamp DESC
+            LIMIT 1;
+        "#)
+            .bind(device)
+            .fetch_optional(&self.pool).await?;
+        let last = match last {
+            Some(last) => last,
+            None => return Ok(None),
+        };
+        Ok(Some((FilePath {
+            year: last.try_get::<i64, _>("year")? as u16,
+            month: last.try_get::<i64, _>("month")? as u8,
+            day: last.try_get::<i64, _>("day")? as u8,
+            timestamp: last.try_get::<i64, _>("file_timestamp")? as u64,
+        }, last.try_get::<i64, _>("measurement_timestamp")? as u64)))
+    }
+
+    async fn store_entries(&self, device: &str, entries: &mut (dyn Stream<Item = (FilePath, Vec<Data14>)> + Unpin + Send), last_timestamp: Option<u64>) -> Result<u64, Error> {
+        let mut stored = 0;
+        while let Some((file, entries)) = entries.next().await {
+            let mut tx = self.pool.begin().await?;
+            sqlx::query("INSERT OR IGNORE INTO files (device, year, month, day, timestamp) VALUES (?, ?, ?, ?, ?);")
+                .bind(device)
+                .bind(file.year as i64)
+                .bind(file.month as i64)
+                .bind(file.day as i64)
+                .bind(file.timestamp as i64)
+                .execute(&mut tx).await?;
+            let file_id: i64 = sqlx::query("SELECT id FROM files WHERE device = ? AND year = ? AND month = ? AND day = ? AND timestamp = ?;")
+                .bind(device)
+                .bind(file.year as i64)
+                .bind(file.month as i64)
+                .bind(file.day as i64)
+                .bind(file.timestamp as i64)
+                .fetch_one(&mut tx).await?
+                .try_get("id")?;
+            for entry in entries.iter().filter(|entry| Some(entry.data11.timestamp) > last_timestamp) {
+                let inserted = insert(entry, Some(file_id)).execute(&mut tx).await?.rows_affected();
+                if inserted == 0 {
+                    // a manual readout is turned into a regular one when the file containing it is downloaded
+                    sqlx::query("UPDATE measurements SET file = ? WHERE device = ? AND timestamp = ? AND file IS NULL;")
+                        .bind(file_id)
+                        .bind(&entry.data11.deviceid)
+                        .bind(entry.data11.timestamp as i64)
+                        .execute(&mut tx).await?;
+                }
+                stored += inserted;
+            }
+            tx.commit().await?;
+        }
+        Ok(stored)
+    }
+
+    async fn store_manual_readout(&self, data: &Data14) -> Result<(), Error> {
+        insert(data, None).execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn apply_retention(&self, cutoffs: &RetentionCutoffs, dry_run: bool) -> Result<RetentionReport, Error> {
+        let mut report = RetentionReport::default();
+        let manual_readouts_before = cutoffs.manual_readouts_before as i64;
+        report.manual_readouts = if dry_run {
+            sqlx::query("SELECT count(*) as count FROM measurements WHERE file IS NULL AND timestamp < ?;")
+                .bind(manual_readouts_before)
+                .fetch_one(&self.pool).await?
+                .try_get::<i64, _>("count")? as u64
+        } else {
+            sqlx::query("DELETE FROM measurements WHERE file IS NULL AND timestamp < ?;")
+                .bind(manual_readouts_before)
+                .execute(&self.pool).await?
+                .rows_affected()
+        };
+
+        let before = match cutoffs.downsample_before {
+            Some(before) => before as i64,
+            None => return Ok(report),
+        };
+        let bucket_millis = cutoffs.downsample_millis as i64;
+        // buckets consisting of a single row were already downsampled
+        let buckets = sqlx::query(r#"
+            SELECT coalesce(sum(count), 0) as rows, count(*) as buckets
+            FROM (
+                SELECT count(*) as count
+                FROM measurements
+                WHERE file IS NOT NULL AND timestamp < ?
+                GROUP BY device, timestamp / ?
+                HAVING count(*) > 1
+            );
+        "#)
+            .bind(before)
+            .bind(bucket_millis)
+            .fetch_one(&self.pool).await?;
+        report.downsampled = buckets.try_get::<i64, _>("rows")? as u64;
+        report.buckets = buckets.try_get::<i64, _>("buckets")? as u64;
+        if dry_run || report.buckets == 0 {
+            return Ok(report);
+        }
+
+        // rows which were already downsampled are weighted by the number of readouts they average
+        let averages: Vec<_> = FLOAT_FIELDS.iter().copied()
+            .chain(["sound", "sound_energy"])
+            .map(|column| format!("{} as {}", weighted_average(column), column))
+            .collect();
+        // the averages are computed into a temporary table first, because a row may be replaced by one with the same timestamp
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(&format!(
+            r#"
+                CREATE TEMPORARY TABLE downsampled AS
+                WITH selected AS (
+                    SELECT *, timestamp / ?2 * ?2 as bucket
+                    FROM measurements
+                    WHERE file IS NOT NULL AND timestamp < ?1
+                ), buckets AS (
+                    SELECT device, bucket FROM selected GROUP BY device, bucket HAVING count(*) > 1
+                ), rest AS (
+                    SELECT device, bucket, json_group_object(key, value) as rest
+                    FROM (
+                        SELECT selected.device, selected.bucket, fields.key,
+                            sum(CASE fields.type WHEN 'array' THEN json_extract(fields.value, '$[0]') ELSE fields.value END * selected.samples)
+                                / sum(selected.samples) as value
+                        FROM selected JOIN buckets USING (device, bucket), json_each(selected.rest) as fields
+                        WHERE CASE fields.type WHEN 'array' THEN json_type(fields.value, '$[0]') ELSE fields.type END IN ('integer', 'real')
+                        GROUP BY selected.device, selected.bucket, fields.key
+                    )
+                    GROUP BY device, bucket
+                )
+                SELECT
+                    device, bucket as timestamp, min(file) as file, {},
+                    max(uptime) as uptime, max(status) as status, sum(samples) as samples, coalesce(rest.rest, '{{}}') as rest
+                FROM selected JOIN buckets USING (device, bucket) LEFT JOIN rest USING (device, bucket)
+                GROUP BY device, bucket;
+            "#,
+            // only `sound_energy` is averaged energetically, it is what is read
+            averages.join(", "),
+        ))
+            .bind(before)
+            .bind(bucket_millis)
+            .execute(&mut tx).await?;
+        sqlx::query(r#"
+            DELETE FROM measurements
+            WHERE file IS NOT NULL AND EXISTS (
+                SELECT 1 FROM downsampled
+                WHERE downsampled.device = measurements.device
+                    AND measurements.timestamp >= downsampled.timestamp AND measurements.timestamp < downsampled.timestamp + ?
+            );
+        "#)
+            .bind(bucket_millis)
+            .execute(&mut tx).await?;
+        sqlx::query(r#"
+            INSERT INTO measurements (
+                device, timestamp, file, health, performance, tvoc, humidity, humidity_abs, temperature, dewpt, sound, sound_energy,
+                pressure, no2, co, co2, pm1, pm2_5, pm10, oxygen, o3, so2,
+                uptime, measuretime, status, bat, door_event, window_open, humidity_abs_delta, co2_delta,
+                cnt0_3, cnt0_5, cnt1, cnt2_5, cnt5, cnt10, typ_ps, rest, samples
+            ) SELECT
+                device, timestamp, file, health, performance, tvoc, humidity, humidity_abs, temperature, dewpt, sound, sound_energy,
+                pressure, no2, co, co2, pm1, pm2_5, pm10, oxygen, o3, so2,
+                uptime, measuretime, status, bat, door_event, window_open, humidity_abs_delta, co2_delta,
+                cnt0_3, cnt0_5, cnt1, cnt2_5, cnt5, cnt10, typ_ps, rest, samples
+            FROM downsampled;
+        "#).execute(&mut tx).await?;
+        sqlx::query("DROP TABLE downsampled;").execute(&mut tx).await?;
+        tx.commit().await?;
+        Ok(report)
+    }
+
+    async fn set_file_state(&self, device: &str, files: &[FilePath], state: SyncState, error: Option<&str>) -> Result<(), Error> {
+        let failed = state == SyncState::Failed;
+        let mut tx = self.pool.begin().await?;
+        for file in files {
+            sqlx::query(r#"
+                INSERT INTO file_sync (device, year, month, day, timestamp, state, attempts, error, updated)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+                ON CONFLICT (device, year, month, day, timestamp) DO UPDATE SET
+                    state = excluded.state,
+                    attempts = file_sync.attempts + excluded.attempts,
+                    -- keep the last error while the file is retried
+                    error = CASE WHEN excluded.state = 'pending' THEN file_sync.error ELSE excluded.error END,
+                    updated = excluded.updated;
+            "#)
+                .bind(device)
+                .bind(file.year as i64)
+                .bind(file.month as i64)
+                .bind(file.day as i64)
+                .bind(file.timestamp as i64)
+                .bind(state.as_str())
+                .bind(failed as i64)
+                .bind(error)
+                .bind(Utc::now().timestamp_millis())
+                .execute(&mut tx).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn unsynced_files(&self, device: &str) -> Result<Vec<FileSync>, Error> {
+        let files = sqlx::query(r#"
+            SELECT year, month, day, timestamp, state, attempts, error
+            FROM file_sync
+            WHERE device = ? AND state <> 'succeeded'
+            ORDER BY year, month, day, timestamp;
+        "#)
+            .bind(device)
+            .fetch_all(&self.pool).await?;
+        files.into_iter()
+            .map(|file| -> Result<_, Error> {
+                Ok(FileSync {
+                    file: FilePath {
+                        year: file.try_get::<i64, _>("year")? as u16,
+                        month: file.try_get::<i64, _>("month")? as u8,
+                        day: file.try_get::<i64, _>("day")? as u8,
+                        timestamp: file.try_get::<i64, _>("timestamp")? as u64,
+                    },
+                    state: SyncState::parse(file.try_get("state")?),
+                    attempts: file.try_get::<i64, _>("attempts")? as u32,
+                    error: file.try_get("error")?,
+                })
+            })
+            .collect()
+    }
+
+    fn pool_stats(&self) -> Option<(u32, usize)> {
+        Some((self.pool.size(), self.pool.num_idle()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream;
+    use super::*;
+    use super::super::test_readout as readout;
+
+    #[rocket::async_test]
+    async fn only_inserted_rows_are_counted() {
+        let path = std::env::temp_dir().join(format!("airq-inserted-{}.sqlite", std::process::id()));
+        let sqlite = Sqlite::open(&path.display().to_string()).await.unwrap();
+        let device = "0123456789abcdef";
+        let file = FilePath { year: 2020, month: 9, day: 13, timestamp: 1_600_000_000 };
+        sqlite.store_manual_readout(&readout(1_600_000_020_000, 700., 1)).await.unwrap();
+        let entries = || vec![(file, vec![readout(1_600_000_020_000, 400., 1), readout(1_600_000_030_000, 500., 2)])];
+        assert_eq!(sqlite.store_entries(device, &mut stream::iter(entries()), None).await.unwrap(), 1);
+        assert_eq!(sqlite.store_entries(device, &mut stream::iter(entries()), None).await.unwrap(), 0);
+
+        // the manual readout is kept, but belongs to the file now
+        let rows = sqlx::query("SELECT co2, file FROM measurements ORDER BY timestamp;").fetch_all(&sqlite.pool).await.unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].get::<f64, _>("co2"), 700.);
+        assert!(rows.iter().all(|row| row.get::<Option<i64>, _>("file").is_some()));
+
+        drop(sqlite);
+        std::fs::remove_file(path).ok();
+    }
+
+    #[rocket::async_test]
+    async fn retention_weights_downsampled_rows() {
+        let path = std::env::temp_dir().join(format!("airq-retention-{}.sqlite", std::process::id()));
+        let sqlite = Sqlite::open(&path.display().to_string()).await.unwrap();
+        let device = "0123456789abcdef";
+        let file = FilePath { year: 2020, month: 9, day: 13, timestamp: 1_600_000_000 };
+        let entries = vec![(file, vec![
+            readout(1_600_000_020_000, 400., 1), readout(1_600_000_030_000, 500., 2), readout(1_600_000_040_000, 600., 3),
+        ])];
+        sqlite.store_entries(device, &mut stream::iter(entries), None).await.unwrap();
+        sqlite.store_manual_readout(&readout(1_600_000_050_000, 1000., 4)).await.unwrap();
+
+        let cutoffs = RetentionCutoffs {
+            manual_readouts_before: 1_600_000_080_000,
+            downsample_before: Some(1_600_000_080_000),
+            downsample_millis: 60_000,
+        };
+        let dry_run = sqlite.apply_retention(&cutoffs, true).await.unwrap();
+        let applied = sqlite.apply_retention(&cutoffs, false).await.unwrap();
+        for report in [&dry_run, &applied] {
+            assert_eq!((report.manual_readouts, report.downsampled, report.buckets), (1, 3, 1));
+        }
+        let again = sqlite.apply_retention(&cutoffs, true).await.unwrap();
+        assert_eq!((again.manual_readouts, again.downsampled, again.buckets), (0, 0, 0));
+
+        // a late row is averaged with the downsampled one weighted by the readouts it replaced
+        let entries = vec![(file, vec![readout(1_600_000_060_000, 800., 5)])];
+        sqlite.store_entries(device, &mut stream::iter(entries), None).await.unwrap();
+        let report = sqlite.apply_retention(&cutoffs, false).await.unwrap();
+        assert_eq!((report.downsampled, report.buckets), (2, 1));
+        let rows = sqlx::query("SELECT co2, uptime, samples, rest FROM measurements;").fetch_all(&sqlite.pool).await.unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].get::<f64, _>("co2"), 575.);
+        assert_eq!(rows[0].get::<i64, _>("uptime"), 5);
+        assert_eq!(rows[0].get::<i64, _>("samples"), 4);
+        let rest: Value = serde_json::from_str(rows[0].get("rest")).unwrap();
+        assert_eq!(rest, serde_json::json!({ "radon": 12.0 }));
+
+        // the downsampled row is weighted by its readouts when averaged with newer ones
+        let entries = vec![(file, vec![readout(1_600_000_130_000, 975., 6)])];
+        sqlite.store_entries(device, &mut stream::iter(entries), None).await.unwrap();
+        let hourly = sqlite.data(device, 1_600_000_000_000, 1_600_000_200_000, 1, 3_600_000).await.unwrap();
+        assert_eq!(hourly.len(), 1);
+        assert_eq!((hourly[0].co2, hourly[0].uptime), (Some(655.), Some(6)));
+        assert_eq!(hourly[0].rest, Some(serde_json::json!({ "radon": 12.0 })));
+
+        drop(sqlite);
+        std::fs::remove_file(path).ok();
+    }
+}
@@ -0,0 +1,216 @@
+use serde::Serialize;
+
+use crate::Measurement;
+
+const HOUR_MILLIS: i64 = 60 * 60 * 1000;
+
+/// Classification of the pressure change over three hours, following the terms used in
+/// shipping forecasts.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TendencyClass {
+    RisingVeryRapidly,
+    RisingQuickly,
+    Rising,
+    RisingSlowly,
+    Steady,
+    FallingSlowly,
+    Falling,
+    FallingQuickly,
+    FallingVeryRapidly,
+}
+
+impl TendencyClass {
+    fn from_change(change: f64) -> TendencyClass {
+        match change {
+            c if c > 6. => TendencyClass::RisingVeryRapidly,
+            c if c >= 3.6 => TendencyClass::RisingQuickly,
+            c if c >= 1.6 => TendencyClass::Rising,
+            c if c >= 0.1 => TendencyClass::RisingSlowly,
+            c if c > -0.1 => TendencyClass::Steady,
+            c if c > -1.6 => TendencyClass::FallingSlowly,
+            c if c > -3.6 => TendencyClass::Falling,
+            c if c >= -6. => TendencyClass::FallingQuickly,
+            _ => TendencyClass::FallingVeryRapidly,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Tendency {
+    /// pressure change over the last three hours in hPa
+    pub change: f64,
+    pub class: TendencyClass,
+    /// characteristic of the pressure tendency according to WMO code table 0200
+    pub code: u8,
+    /// pressure reduced to sea level in hPa
+    pub sea_level_pressure: f64,
+    /// Zambretti forecast number between 1 and 32
+    pub zambretti: u8,
+    pub forecast: &'static str,
+}
+
+/// Computes the pressure tendency at `at` from the pressure curve of the preceding three hours.
+///
+/// `measurements` must be sorted by timestamp.
+/// Pressures are linearly interpolated between measurements at most `max_gap` milliseconds apart.
+pub fn tendency(measurements: &[Measurement], at: i64, max_gap: i64, altitude: f64) -> Option<Tendency> {
+    let pressures: Vec<_> = measurements.iter()
+        .filter_map(|m| Some((m.timestamp?, m.pressure?, m.temperature)))
+        .collect();
+    let pressure_at = |timestamp: i64| -> Option<f64> {
+        let next = pressures.iter().position(|&(ts, _, _)| ts >= timestamp)?;
+        let (ts1, p1, _) = pressures[next];
+        if ts1 == timestamp {
+            return Some(p1);
+        }
+        let (ts0, p0, _) = pressures[next.checked_sub(1)?];
+        if ts1 - ts0 > max_gap {
+            return None;
+        }
+        Some(p0 + (p1 - p0) * (timestamp - ts0) as f64 / (ts1 - ts0) as f64)
+    };
+
+    let p0 = pressure_at(at - 3 * HOUR_MILLIS)?;
+    let p1 = pressure_at(at - 3 * HOUR_MILLIS / 2)?;
+    let p2 = pressure_at(at)?;
+    let temperature = pressures.iter().rev()
+        .find(|&&(ts, _, _)| ts <= at)
+        .and_then(|&(_, _, temperature)| temperature)
+        .unwrap_or(15.);
+
+    let change = p2 - p0;
+    let sea_level_pressure = sea_level_pressure(p2, temperature, altitude);
+    let (zambretti, forecast) = zambretti(sea_level_pressure, change);
+    Some(Tendency {
+        change,
+        class: TendencyClass::from_change(change),
+        code: wmo_code(p1 - p0, p2 - p1, change),
+        sea_level_pressure,
+        zambretti,
+        forecast,
+    })
+}
+
+/// WMO code table 0200, determined from the change in the first and second half of the three hours.
+fn wmo_code(first_half: f64, second_half: f64, change: f64) -> u8 {
+    const STEADY: f64 = 0.1;
+    let rising = |d: f64| d >= STEADY;
+    let falling = |d: f64| d <= -STEADY;
+    if change.abs() < STEADY {
+        return 4;
+    }
+    if change > 0. {
+        match () {
+            _ if rising(first_half) && falling(second_half) => 0,
+            _ if rising(first_half) && !rising(second_half) => 1,
+            _ if rising(first_half) => 2,
+            _ => 3,
+        }
+    } else {
+        match () {
+            _ if falling(first_half) && rising(second_half) => 5,
+            _ if falling(first_half) && !falling(second_half) => 6,
+            _ if falling(first_half) => 7,
+            _ => 8,
+        }
+    }
+}
+
+/// Reduces the pressure measured at `altitude` meters to sea level using the barometric formula.
+fn sea_level_pressure(pressure: f64, temperature: f64, altitude: f64) -> f64 {
+    pressure * (1. - 0.0065 * altitude / (temperature + 0.0065 * altitude + 273.15)).powf(-5.257)
+}
+
+/// Zambretti forecast from the sea level pressure and the three hour pressure change,
+/// without wind and season corrections.
+fn zambretti(pressure: f64, change: f64) -> (u8, &'static str) {
+    const FORECASTS: [&str; 26] = [
+        "Settled fine", "Fine weather", "Becoming fine", "Fine, becoming less settled",
+        "Fine, possible showers", "Fairly fine, improving", "Fairly fine, possible showers early",
+        "Fairly fine, showery later", "Showery early, improving", "Changeable, mending",
+        "Fairly fine, showers likely", "Rather unsettled clearing later", "Unsettled, probably improving",
+        "Showery, bright intervals", "Showery, becoming less settled", "Changeable, some rain",
+        "Unsettled, short fine intervals", "Unsettled, rain later", "Unsettled, some rain",
+        "Mostly very unsettled", "Occasional rain, worsening", "Rain at times, very unsettled",
+        "Rain at frequent intervals", "Rain, very unsettled", "Stormy, may improve", "Stormy, much rain",
+    ];
+    // indices into FORECASTS (letters A-Z) of the falling, steady and rising tables
+    const FALLING: [usize; 9] = [0, 1, 3, 7, 14, 17, 20, 23, 25];
+    const STEADY: [usize; 10] = [0, 1, 4, 10, 13, 15, 18, 22, 23, 25];
+    const RISING: [usize; 13] = [0, 1, 2, 5, 6, 8, 9, 11, 12, 16, 19, 24, 25];
+
+    let (z, first, table): (f64, u8, &[usize]) = if change <= -1.6 {
+        (127. - 0.12 * pressure, 1, &FALLING)
+    } else if change >= 1.6 {
+        (185. - 0.16 * pressure, 20, &RISING)
+    } else {
+        (144. - 0.13 * pressure, 10, &STEADY)
+    };
+    let index = (z.round() as i64 - first as i64).max(0).min(table.len() as i64 - 1) as usize;
+    (first + index as u8, FORECASTS[table[index]])
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    /// Measurements every 2 minutes over the three hours before `at` with the pressure given by `pressure`.
+    fn measurements(at: i64, pressure: impl Fn(f64) -> f64) -> Vec<Measurement> {
+        (0..=90)
+            .map(|i| {
+                let timestamp = at - 3 * HOUR_MILLIS + i * 2 * 60 * 1000;
+                let hours = (timestamp - at) as f64 / HOUR_MILLIS as f64 + 3.;
+                serde_json::from_value(json!({ "timestamp": timestamp, "pressure": pressure(hours), "temperature": 15.0 })).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn steady_rising_and_falling_pressure() {
+        let at = 1_600_000_000_000;
+        let rising = tendency(&measurements(at, |hours| 1000. + hours), at, 30 * 60 * 1000, 0.).unwrap();
+        assert!((rising.change - 3.).abs() < 1e-9);
+        assert!(matches!(rising.class, TendencyClass::Rising));
+        assert_eq!(rising.code, 2);
+        assert!((rising.sea_level_pressure - 1003.).abs() < 1e-9);
+
+        let steady = tendency(&measurements(at, |_| 1013.), at, 30 * 60 * 1000, 0.).unwrap();
+        assert!(matches!(steady.class, TendencyClass::Steady));
+        assert_eq!(steady.code, 4);
+
+        // falling, then steady
+        let falling = tendency(&measurements(at, |hours| 1010. - 2. * hours.min(1.5)), at, 30 * 60 * 1000, 0.).unwrap();
+        assert!((falling.change + 3.).abs() < 1e-9);
+        assert!(matches!(falling.class, TendencyClass::Falling));
+        assert_eq!(falling.code, 6);
+    }
+
+    #[test]
+    fn no_tendency_across_gaps() {
+        let at = 1_600_000_000_000;
+        let mut measurements = measurements(at, |hours| 1000. + hours);
+        // an hour without measurements around the middle
+        measurements.retain(|m| (m.timestamp.unwrap() - (at - 3 * HOUR_MILLIS / 2)).abs() > HOUR_MILLIS / 2);
+        assert!(tendency(&measurements, at, 30 * 60 * 1000, 0.).is_none());
+        assert!(tendency(&[], at, 30 * 60 * 1000, 0.).is_none());
+    }
+
+    #[test]
+    fn sea_level_pressure_increases_with_altitude() {
+        assert_eq!(sea_level_pressure(1013.25, 15., 0.), 1013.25);
+        // about 1 hPa per 8 m near sea level
+        assert!((sea_level_pressure(1000., 15., 100.) - 1012.).abs() < 0.5);
+    }
+
+    #[test]
+    fn zambretti_forecasts() {
+        assert_eq!(zambretti(1013., 0.), (12, "Fine, possible showers"));
+        assert_eq!(zambretti(1030., 2.), (20, "Settled fine"));
+        assert_eq!(zambretti(990., -2.), (8, "Rain, very unsettled"));
+        // out of range pressures are clamped to the first and last forecast of the table
+        assert_eq!(zambretti(1080., -2.), (1, "Settled fine"));
+        assert_eq!(zambretti(940., 2.), (32, "Stormy, much rain"));
+    }
+}